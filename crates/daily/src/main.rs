@@ -6,12 +6,13 @@ extern crate robohome_shared;
 
 use env_logger::{Builder, Target};
 
-use robohome_shared::{data::*, error::Error};
+use robohome_shared::{clock::SystemClock, data::*, error::Error};
 
 fn main() -> Result<(), Error> {
     init_logging();
-    let (sunrise, sunset) = get_daily_info()?;
-    let ct = save_daily_info(sunrise, sunset)?;
+    let clock = SystemClock;
+    let (sunrise, sunset) = get_daily_info(&clock)?;
+    let ct = save_daily_info(&clock, sunrise, sunset)?;
     info!(target: "robohome:info", "saved daily info with {} times", ct);
     Ok(())
 }
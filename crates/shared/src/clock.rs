@@ -0,0 +1,76 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration as StdDuration,
+};
+
+use chrono::{DateTime, Duration, Utc};
+use chrono_tz::Tz;
+
+use super::CONFIG;
+
+/// Where everything that cares about the time of day gets
+/// it from, so the real clock can be swapped out for one
+/// that is driven by hand
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+    fn sleep(&self, dur: StdDuration);
+}
+
+pub type SharedClock = Arc<dyn Clock>;
+
+/// The current time in the home timezone
+pub fn home_now(clock: &dyn Clock) -> DateTime<Tz> {
+    clock.now().with_timezone(&CONFIG.timezone())
+}
+
+/// The wall clock
+pub struct SystemClock;
+
+impl SystemClock {
+    pub fn shared() -> SharedClock {
+        Arc::new(SystemClock)
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn sleep(&self, dur: StdDuration) {
+        ::std::thread::sleep(dur)
+    }
+}
+
+/// A clock that only moves when told to, sleeping on it
+/// moves it forward by the requested amount immediately
+pub struct ManualClock {
+    now: Mutex<DateTime<Utc>>,
+}
+
+impl ManualClock {
+    pub fn new(start: DateTime<Utc>) -> Self {
+        Self {
+            now: Mutex::new(start),
+        }
+    }
+
+    pub fn set(&self, to: DateTime<Utc>) {
+        *self.now.lock().expect("ManualClock lock poisoned") = to;
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().expect("ManualClock lock poisoned");
+        *now = *now + by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().expect("ManualClock lock poisoned")
+    }
+
+    fn sleep(&self, dur: StdDuration) {
+        self.advance(Duration::from_std(dur).unwrap_or_else(|_| Duration::zero()));
+    }
+}
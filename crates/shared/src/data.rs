@@ -1,23 +1,25 @@
-use chrono::{DateTime, NaiveDateTime, Local, Datelike, Timelike, Weekday};
-use postgres::{Connection, TlsMode};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc, Datelike, Timelike, Weekday};
+use postgres::{Connection, TlsMode, rows::Row};
+
+use std::sync::Arc;
 #[cfg(feature = "web")]
 use reqwest::{get};
 
-use super::{CONFIG, error::Error};
+use super::{CONFIG, clock::{Clock, home_now}, error::Error, message::{FlipCommand, Outcome}};
 #[cfg(feature = "web")]
-pub fn check_for_daily_info() -> Result<bool, Error> {
+pub fn check_for_daily_info(clock: &dyn Clock) -> Result<bool, Error> {
     let c = get_conn()?;
-    let today = Local::today().naive_local().and_hms(0,0,0);
+    let today = home_now(clock).date().naive_local().and_hms(0,0,0);
     debug!(target: "robohome->debug", "check_for_daily_info today");
     let count = c.query(r#"SELECT "Id" from "KeyTimes" WHERE "Date" = $1"#, &[&today])?.len();
     Ok(count != 4)
 }
 #[cfg(feature = "web")]
-pub fn get_daily_info() -> Result<(Time, Time), Error> {
+pub fn get_daily_info(clock: &dyn Clock) -> Result<(Time, Time), Error> {
     debug!(target: "robohome:debug", "get_daily_info");
     let ret: WeatherResponse = request_weather()?;
-    let sunrise = Time::from(ret.sun_phase.sunrise.into()?, TimeKind::Sunrise);
-    let sunset = Time::from(ret.sun_phase.sunset.into()?, TimeKind::Sunset);
+    let sunrise = Time::from(ret.sun_phase.sunrise.into(clock)?, TimeKind::Sunrise);
+    let sunset = Time::from(ret.sun_phase.sunset.into(clock)?, TimeKind::Sunset);
     Ok((sunrise, sunset))
 }
 #[cfg(feature = "web")]
@@ -41,10 +43,10 @@ pub fn request_weather() -> Result<WeatherResponse, Error> {
     Err(Error::Other(String::from("exceeded total request attempts")))
 }
 
-pub fn save_daily_info(sunrise: Time, sunset: Time) -> Result<i32, Error> {
+pub fn save_daily_info(clock: &dyn Clock, sunrise: Time, sunset: Time) -> Result<i32, Error> {
     debug!(target: "robohome:debug", "save_daily_info");
     let c = get_conn()?;
-    let local = Local::today();
+    let local = home_now(clock).date();
     let today = local.naive_local().and_hms(0,0,0);
     let trans = c.transaction()?;
     let stmt = trans.prepare(r#"INSERT INTO public."KeyTimes" ("Date", "Time_Hour", "Time_Minute",
@@ -66,36 +68,121 @@ pub fn save_daily_info(sunrise: Time, sunset: Time) -> Result<i32, Error> {
     Ok(count)
 }
 
-pub fn get_flips() -> Result<Vec<Flip>, Error> {
+/// Where the Flipper gets each day's flips from, so the
+/// database can be swapped out when testing
+pub trait FlipSource: Send + Sync {
+    fn flips(&self, day: NaiveDate, profile: Option<&str>) -> Result<Vec<Flip>, Error>;
+}
+
+pub type SharedFlipSource = Arc<dyn FlipSource>;
+
+/// Flips stored in the database, see `get_flips`
+pub struct DbFlipSource;
+
+impl DbFlipSource {
+    pub fn shared() -> SharedFlipSource {
+        Arc::new(DbFlipSource)
+    }
+}
+
+impl FlipSource for DbFlipSource {
+    fn flips(&self, day: NaiveDate, profile: Option<&str>) -> Result<Vec<Flip>, Error> {
+        get_flips(day, profile)
+    }
+}
+
+/// Get the flips for a schedule day, see `schedule::schedule_date`,
+/// flips tagged with a profile other than `profile` are left out
+pub fn get_flips(day: NaiveDate, profile: Option<&str>) -> Result<Vec<Flip>, Error> {
     debug!(target: "robohome:debug", "get_flips");
     let c = get_conn()?;
-    let dow = get_dow();
-    let rows = c.query(r#"SELECT id, direction, hour, min, tod, kind, dow, switch_id, remote_id
-                FROM PendingFlips
-                WHERE dow & $1 > 0"#, &[&dow])?;
+    let dow = get_dow(day);
+    // PendingFlips belongs to the web app so the columns
+    // this adds to "Flips" are read from the table itself
+    let rows = c.query(r#"SELECT p.id, p.direction, p.hour, p.min, p.tod, p.kind, p.dow, p.switch_id, p.remote_id, f."Priority"
+                FROM PendingFlips AS p
+                JOIN "Flips" AS f ON f."Id" = p.id
+                WHERE p.dow & $1 > 0
+                AND (f."Profile" IS NULL OR f."Profile" = $2)"#, &[&dow, &profile])?;
     let mut ret = Vec::with_capacity(rows.len());
     for r in &rows {
-        let id = r.get(0);
-        let direction = r.get(1);
-        let hour = r.get(2);
-        let min = r.get(3);
-        let tod = r.get(4);
-        let kind = r.get(5);
-        let dow = r.get(6);
-        let sw_id = r.get(7);
-        let rm_id = r.get(8);
-        ret.push(Flip::from_db(id, direction, hour, min, tod, kind, dow, sw_id, rm_id)?)
+        ret.push(Flip::from_row(&r)?)
     }
     Ok(ret)
 }
 
+/// Every switch that was on when it was last seen,
+/// along with the time it was turned on
+pub fn get_on_times() -> Result<Vec<(i32, i32, DateTime<Utc>)>, Error> {
+    debug!(target: "robohome:debug", "get_on_times");
+    let c = get_conn()?;
+    let rows = c.query(r#"SELECT "RemoteId", "SwitchId", "OnSince" FROM "SwitchOnTimes""#, &[])?;
+    Ok(rows.iter().map(|r| (r.get(0), r.get(1), r.get(2))).collect())
+}
+
+/// Save the time a switch was turned on, `None` clears
+/// it when the switch is turned off
+pub fn save_on_time(remote_id: i32, switch_id: i32, on_since: Option<DateTime<Utc>>) -> Result<(), Error> {
+    debug!(target: "robohome:debug", "save_on_time");
+    let c = get_conn()?;
+    if let Some(on_since) = on_since {
+        c.execute(r#"INSERT INTO "SwitchOnTimes" ("RemoteId", "SwitchId", "OnSince")
+                    VALUES ($1, $2, $3)
+                    ON CONFLICT ("RemoteId", "SwitchId") DO UPDATE SET "OnSince" = EXCLUDED."OnSince""#,
+                    &[&remote_id, &switch_id, &on_since])?;
+    } else {
+        c.execute(r#"DELETE FROM "SwitchOnTimes" WHERE "RemoteId" = $1 AND "SwitchId" = $2"#,
+                    &[&remote_id, &switch_id])?;
+    }
+    Ok(())
+}
+
+/// Record what happened to a command, `outcome` is a
+/// short human readable description like "sent"
+pub fn record_history(cmd: &FlipCommand, outcome: &Outcome, at: DateTime<Utc>) -> Result<(), Error> {
+    debug!(target: "robohome:debug", "record_history");
+    let c = get_conn()?;
+    c.execute(r#"INSERT INTO "FlipHistory" ("FlipId", "RemoteId", "SwitchId", "Direction", "Reason", "Outcome", "At")
+                VALUES ($1, $2, $3, $4, $5, $6, $7)"#,
+                &[&cmd.flip_id, &cmd.remote_id, &cmd.switch_id, &cmd.direction.for_db(),
+                  &cmd.reason.to_string(), &outcome.to_string(), &at])?;
+    Ok(())
+}
+
+/// The latest command sent to a switch between `after` and
+/// `before` that didn't come from the schedule
+pub fn get_last_override(remote_id: i32, switch_id: i32,
+                        after: DateTime<Utc>, before: DateTime<Utc>) -> Result<Option<(SwitchState, DateTime<Utc>, String)>, Error> {
+    debug!(target: "robohome:debug", "get_last_override");
+    let c = get_conn()?;
+    let rows = c.query(r#"SELECT "Direction", "At", "Reason" FROM "FlipHistory"
+                WHERE "RemoteId" = $1 AND "SwitchId" = $2
+                AND "Outcome" = 'sent' AND "Reason" <> 'schedule'
+                AND "At" > $3 AND "At" <= $4
+                ORDER BY "At" DESC
+                LIMIT 1"#, &[&remote_id, &switch_id, &after, &before])?;
+    if let Some(r) = rows.iter().next() {
+        let direction = SwitchState::from_db(r.get(0))?;
+        Ok(Some((direction, r.get(1), r.get(2))))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Check the database can be reached
+pub fn ping() -> Result<(), Error> {
+    let c = get_conn()?;
+    c.execute("SELECT 1", &[])?;
+    Ok(())
+}
+
 pub fn get_conn() -> Result<Connection, Error> {
     let c = Connection::connect(CONFIG.db_conn_str.as_str(), TlsMode::None)?;
     Ok(c)
 }
 
-fn get_dow() -> i32 {
-    match Local::today().weekday() {
+fn get_dow(day: NaiveDate) -> i32 {
+    match day.weekday() {
         Weekday::Sun => 1,
         Weekday::Mon => 2,
         Weekday::Tue => 4,
@@ -124,8 +211,8 @@ pub struct WeatherTime {
 }
 
 impl WeatherTime {
-    fn into(self) -> Result<NaiveDateTime, Error> {
-        let ret = Local::today().naive_local();
+    fn into(self, clock: &dyn Clock) -> Result<NaiveDateTime, Error> {
+        let ret = home_now(clock).date().naive_local();
         if let Ok(hour) = self.hour.parse() {
             if let Ok(min) = self.minute.parse() {
                 return Ok(ret.and_hms(hour, min, 0));
@@ -159,6 +246,15 @@ impl Time {
             day_of_week: dow,
         }
     }
+    /// Build a time from the number of minutes after midnight
+    pub fn at_minute(minute: i32, kind: TimeKind, dow: i32) -> Self {
+        let minute = minute.rem_euclid(24 * 60);
+        let hour = minute / 60;
+        let tod = if hour >= 12 { TimeOfDay::Pm } else { TimeOfDay::Am };
+        let hour = if hour % 12 == 0 { 12 } else { hour % 12 };
+        Self::new(hour, minute % 60, tod, kind, dow)
+    }
+
     fn from(dt: NaiveDateTime, kind: TimeKind) -> Self {
         let (is_pm, hour) = dt.hour12();
         let min = dt.minute() as i32;
@@ -167,18 +263,20 @@ impl Time {
         Self::new(hour as i32, min, tod, kind, dow)
     }
 
-    pub fn lte(&self, other: &DateTime<Local>) -> bool {
-        let hour = if self.tod == TimeOfDay::Pm {
-            self.hour + 12
-        } else {
-            self.hour
-        };
-        let other_h = other.hour() as i32;
-        let other_min = other.minute() as i32;
-        hour <= other_h && self.minute <= other_min
+    /// The number of minutes after midnight this time
+    /// falls on, 12 AM is midnight and 12 PM is noon
+    pub fn minute_of_day(&self) -> i32 {
+        let hour = self.hour % 12 + if self.tod == TimeOfDay::Pm { 12 } else { 0 };
+        hour * 60 + self.minute
+    }
+
+    /// If this time can actually happen on a clock
+    pub fn is_valid(&self) -> bool {
+        self.hour >= 1 && self.hour <= 12 && self.minute >= 0 && self.minute < 60
     }
+
 }
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
 pub enum TimeKind {
     Custom,
     Dawn,
@@ -215,7 +313,7 @@ impl TimeKind {
         }
     }
 }
-#[derive(Serialize, PartialEq, Debug)]
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
 pub enum TimeOfDay {
     Am,
     Pm,
@@ -245,26 +343,28 @@ pub struct Flip {
     pub time: Time,
     pub switch_id: i32,
     pub remote_id: i32,
+    /// When two flips for the same switch land on the
+    /// same minute the higher priority wins
+    pub priority: i32,
 }
 
 impl Flip {
-    pub fn from_db(id: i32, direction: i32,
-                hour: i32, min: i32, tod: i32,
-                time_kind: i32, dow: i32 ,
-                switch_id: i32, remote_id: i32) -> Result<Self, Error> {
-        let time = Time::from_db(hour, min, tod, time_kind, dow)?;
-        let direction = SwitchState::from_db(direction)?;
+    /// Build a flip from a row of `get_flips`
+    pub fn from_row(r: &Row) -> Result<Self, Error> {
+        let time = Time::from_db(r.get(2), r.get(3), r.get(4), r.get(5), r.get(6))?;
+        let direction = SwitchState::from_db(r.get(1))?;
         Ok(Self {
-            id,
+            id: r.get(0),
             direction,
             time,
-            switch_id,
-            remote_id,
+            switch_id: r.get(7),
+            remote_id: r.get(8),
+            priority: r.get(9),
         })
     }
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
 pub enum SwitchState {
     Off,
     On,
@@ -1,12 +1,12 @@
 #[cfg(feature = "web")]
 use reqwest::Error as RError;
-use postgres::Error as PError;
+use postgres::{Error as PError, error::{UNDEFINED_COLUMN, UNDEFINED_FUNCTION, UNDEFINED_TABLE}};
 use amqp::AMQPError as AError;
 use serde_json::Error as JError;
 
 use std::sync::mpsc::{RecvError, SendError};
 
-use super::message::ChannelMessage;
+use super::message::{ChannelMessage, Severity};
 
 #[derive(Debug)]
 pub enum Error {
@@ -17,6 +17,9 @@ pub enum Error {
     Send(SendError<ChannelMessage>),
     Rec(RecvError),
     Enum(String, i32),
+    /// The broker returned a published message
+    /// because nothing was bound to receive it
+    Undelivered(String),
     Other(String),
 }
 
@@ -27,6 +30,24 @@ impl Error {
     pub fn _enum(name: &str, i: i32) -> Self {
         Error::Enum(name.to_owned(), i)
     }
+    /// A worker can carry on after most errors, losing its
+    /// channel to the Supervisor stops the worker and a database
+    /// missing the tables, columns or functions we expect won't
+    /// get better by restarting so it stops the switcher
+    pub fn severity(&self) -> Severity {
+        match self {
+            Error::Send(_) | Error::Rec(_) => Severity::WorkerFatal,
+            Error::Db(e) if is_schema_error(e) => Severity::Unrecoverable,
+            _ => Severity::Recoverable,
+        }
+    }
+}
+
+fn is_schema_error(e: &PError) -> bool {
+    match e.code() {
+        Some(code) => *code == UNDEFINED_TABLE || *code == UNDEFINED_COLUMN || *code == UNDEFINED_FUNCTION,
+        None => false,
+    }
 }
 
 impl ::std::error::Error for Error {
@@ -52,6 +73,7 @@ impl ::std::fmt::Display for Error {
             Error::Send(e) => write!(f, "MCSP Channel Send Error\n{}", e),
             Error::Rec(e) => write!(f, "MCSP Channel Recv Error\n{}", e),
             Error::Enum(name, idx) => write!(f, "Attempt to construct {} failed with {}, out of bounds", idx, name),
+            Error::Undelivered(s) => write!(f, "MQ Delivery Error\n{}", s),
             Error::Other(s) => write!(f, "Unknown Error\n{}", s),
         }
     }
@@ -1,5 +1,6 @@
 extern crate amqp;
 extern crate chrono;
+extern crate chrono_tz;
 extern crate env_logger;
 #[macro_use]
 extern crate lazy_static;
@@ -15,22 +16,379 @@ extern crate serde_json;
 extern crate toml;
 extern crate uuid;
 use toml::from_str;
+use chrono::NaiveTime;
+use chrono_tz::Tz;
+use serde::{Deserialize, Deserializer, de::Error as DeError};
 
 lazy_static! {
     pub static ref CONFIG: Config = from_str(include_str!("../config.toml")).expect("Unable to deserialize config.toml");
 }
 
+pub mod clock;
 pub mod data;
 pub mod error;
 pub mod message;
+pub mod query;
+pub mod schedule;
+pub mod solar;
 
 #[derive(Deserialize)]
 pub struct Config {
+    /// The IANA name of the timezone the house is in,
+    /// all dates and times are worked out in this zone.
+    /// When missing the host's timezone is used
+    #[serde(default = "default_timezone", deserialize_with = "deserialize_timezone")]
+    timezone: Tz,
+    /// Where the house is, used to work out solar times for
+    /// days other than today. When missing the times last
+    /// saved by the daily updater are used for every day
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
     pub db_conn_str: String,
     pub weather_uri: String,
     weather_attempts: usize,
     pub mq_config: MqConfig,
     pub log_arg: Option<String>,
+    #[serde(default)]
+    pub dispatch: DispatchConfig,
+    #[serde(default)]
+    pub feedback: FeedbackConfig,
+    #[serde(default)]
+    pub presence: PresenceConfig,
+    #[serde(default)]
+    pub interlocks: Vec<Interlock>,
+    /// The time of day a schedule day starts, flips
+    /// before this run on the following calendar day
+    #[serde(default = "default_day_start", deserialize_with = "deserialize_day_start")]
+    day_start: NaiveTime,
+    #[serde(default)]
+    pub dst: DstConfig,
+    /// Named sets of switch states that can be
+    /// applied all at once with a `run-scene` command
+    #[serde(default)]
+    pub scenes: Vec<Scene>,
+    #[serde(default)]
+    pub status: StatusConfig,
+    /// The schedule profile to start in, flips tagged with
+    /// another profile are ignored. When missing only the
+    /// flips without a profile are used
+    pub profile: Option<String>,
+}
+
+/// What to do with flips that land on a time the clocks
+/// skip or repeat when daylight saving time changes
+#[derive(Deserialize, Default)]
+pub struct DstConfig {
+    #[serde(default)]
+    pub nonexistent: Nonexistent,
+    #[serde(default)]
+    pub ambiguous: Ambiguous,
+}
+
+/// A time that is skipped when the clocks spring forward
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Nonexistent {
+    /// Don't send the flip that day
+    Skip,
+    /// Send the flip at the first minute after the gap
+    #[default]
+    ShiftForward,
+}
+
+/// A time that happens twice when the clocks fall back,
+/// either way the flip is only sent once
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Ambiguous {
+    /// Send the flip the first time the clock reads that time
+    #[default]
+    Earliest,
+    /// Send the flip the second time the clock reads that time
+    Latest,
+}
+
+impl Config {
+    pub fn timezone(&self) -> Tz {
+        self.timezone
+    }
+
+    pub fn scene(&self, name: &str) -> Option<&Scene> {
+        self.scenes.iter().find(|s| s.name == name)
+    }
+
+    pub fn day_start(&self) -> NaiveTime {
+        self.day_start
+    }
+}
+
+fn default_day_start() -> NaiveTime {
+    NaiveTime::from_hms(0, 0, 0)
+}
+
+/// The timezone the host is set to, everything was worked out
+/// in it before `timezone` could be set so older configs keep
+/// behaving the same. UTC when the host doesn't say
+fn default_timezone() -> Tz {
+    host_timezone().unwrap_or(Tz::UTC)
+}
+
+fn host_timezone() -> Option<Tz> {
+    let from_env = ::std::env::var("TZ").ok()
+        .and_then(|name| name.trim_start_matches(':').parse().ok());
+    let from_file = || ::std::fs::read_to_string("/etc/timezone").ok()
+        .and_then(|name| name.trim().parse().ok());
+    let from_link = || {
+        let link = ::std::fs::read_link("/etc/localtime").ok()?;
+        let link = link.to_string_lossy();
+        let start = link.find("zoneinfo/")? + "zoneinfo/".len();
+        link[start..].parse().ok()
+    };
+    from_env.or_else(from_file).or_else(from_link)
+}
+
+fn deserialize_timezone<'de, D>(deserializer: D) -> Result<Tz, D::Error>
+where D: Deserializer<'de> {
+    let name = String::deserialize(deserializer)?;
+    name.parse()
+        .map_err(|e| D::Error::custom(format!("timezone must be an IANA timezone name: {}", e)))
+}
+
+fn deserialize_day_start<'de, D>(deserializer: D) -> Result<NaiveTime, D::Error>
+where D: Deserializer<'de> {
+    let time = String::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&time, "%H:%M")
+        .map_err(|e| D::Error::custom(format!("day_start must be formatted as HH:MM: {}", e)))
+}
+
+/// A single switch on a single remote
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+pub struct SwitchRef {
+    pub remote_id: i32,
+    pub switch_id: i32,
+}
+
+impl ::std::fmt::Display for SwitchRef {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "{}:{}", self.remote_id, self.switch_id)
+    }
+}
+
+/// Controls the status message published for monitoring
+#[derive(Deserialize)]
+pub struct StatusConfig {
+    /// How often the status is published, 0 turns it off
+    #[serde(default = "default_status_interval_secs")]
+    pub interval_secs: u64,
+    /// The key the status is published to the exchange with
+    #[serde(default = "default_status_routing_key")]
+    pub routing_key: String,
+}
+
+impl Default for StatusConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: default_status_interval_secs(),
+            routing_key: default_status_routing_key(),
+        }
+    }
+}
+
+fn default_status_interval_secs() -> u64 {
+    60
+}
+
+fn default_status_routing_key() -> String {
+    String::from("status")
+}
+
+/// A set of switch states applied together
+#[derive(Deserialize, Debug)]
+pub struct Scene {
+    pub name: String,
+    pub switches: Vec<SceneSwitch>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SceneSwitch {
+    pub remote_id: i32,
+    pub switch_id: i32,
+    pub state: data::SwitchState,
+}
+
+/// A rule about which switches are allowed
+/// to be on at the same time
+#[derive(Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Interlock {
+    /// At most one of these switches can be on
+    Exclusive { switches: Vec<SwitchRef> },
+    /// `switch` can only be on while `requires` is on
+    Requires { switch: SwitchRef, requires: SwitchRef },
+    /// No more than `max` of these switches can be on
+    MaxOn { max: usize, switches: Vec<SwitchRef> },
+}
+
+/// Controls how state reports from the remotes are
+/// compared against what was sent to them
+#[derive(Deserialize)]
+pub struct FeedbackConfig {
+    /// How long a switch has to report the commanded
+    /// state before it is considered mismatched
+    #[serde(default = "default_grace_secs")]
+    pub grace_secs: i64,
+    /// Send the commanded state again on a mismatch
+    #[serde(default)]
+    pub resend_on_mismatch: bool,
+    #[serde(default = "default_max_resends")]
+    pub max_resends: u8,
+}
+
+impl Default for FeedbackConfig {
+    fn default() -> Self {
+        Self {
+            grace_secs: default_grace_secs(),
+            resend_on_mismatch: false,
+            max_resends: default_max_resends(),
+        }
+    }
+}
+
+/// Controls how remote heartbeats are interpreted and
+/// what happens to commands for remotes that are offline
+#[derive(Deserialize)]
+pub struct PresenceConfig {
+    /// How long a remote can go without a heartbeat
+    /// before it is considered offline
+    #[serde(default = "default_presence_timeout_secs")]
+    pub timeout_secs: i64,
+    /// How long a command is held for an offline remote
+    /// before it is dropped as stale
+    #[serde(default = "default_hold_expiry_secs")]
+    pub hold_expiry_secs: i64,
+    /// Treat remotes that have never sent a heartbeat
+    /// as online
+    #[serde(default = "default_true")]
+    pub assume_online: bool,
+}
+
+impl Default for PresenceConfig {
+    fn default() -> Self {
+        Self {
+            timeout_secs: default_presence_timeout_secs(),
+            hold_expiry_secs: default_hold_expiry_secs(),
+            assume_online: true,
+        }
+    }
+}
+
+fn default_presence_timeout_secs() -> i64 {
+    90
+}
+
+fn default_hold_expiry_secs() -> i64 {
+    60 * 60
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_grace_secs() -> i64 {
+    30
+}
+
+fn default_max_resends() -> u8 {
+    3
+}
+
+/// Controls how commands are fed out to each remote
+#[derive(Deserialize)]
+pub struct DispatchConfig {
+    /// The minimum number of milliseconds between two
+    /// commands sent to the same remote
+    #[serde(default = "default_min_gap_ms")]
+    pub min_gap_ms: u64,
+    #[serde(default)]
+    pub remotes: Vec<RemoteConfig>,
+    #[serde(default)]
+    pub switches: Vec<SwitchConfig>,
+}
+
+impl DispatchConfig {
+    pub fn remote(&self, remote_id: i32) -> Option<&RemoteConfig> {
+        self.remotes.iter().find(|r| r.id == remote_id)
+    }
+
+    pub fn switch(&self, remote_id: i32, switch_id: i32) -> Option<&SwitchConfig> {
+        self.switches.iter().find(|s| s.remote_id == remote_id && s.switch_id == switch_id)
+    }
+
+    pub fn min_gap_ms(&self, remote_id: i32) -> u64 {
+        self.remote(remote_id)
+            .and_then(|r| r.min_gap_ms)
+            .unwrap_or(self.min_gap_ms)
+    }
+
+    /// How many extra copies of each command should be
+    /// sent to this switch, a switch's setting wins over
+    /// its remote's
+    pub fn repeat(&self, remote_id: i32, switch_id: i32) -> u8 {
+        self.switch(remote_id, switch_id)
+            .and_then(|s| s.repeat)
+            .or_else(|| self.remote(remote_id).and_then(|r| r.repeat))
+            .unwrap_or(0)
+    }
+
+    pub fn always_send(&self, remote_id: i32, switch_id: i32) -> bool {
+        self.switch(remote_id, switch_id).map(|s| s.always_send).unwrap_or(false)
+    }
+
+    /// The time to wait between the copies of a repeated
+    /// command, defaults to the remote's minimum gap
+    pub fn repeat_gap_ms(&self, remote_id: i32, switch_id: i32) -> u64 {
+        self.switch(remote_id, switch_id)
+            .and_then(|s| s.repeat_gap_ms)
+            .or_else(|| self.remote(remote_id).and_then(|r| r.repeat_gap_ms))
+            .unwrap_or_else(|| self.min_gap_ms(remote_id))
+    }
+}
+
+impl Default for DispatchConfig {
+    fn default() -> Self {
+        Self {
+            min_gap_ms: default_min_gap_ms(),
+            remotes: vec![],
+            switches: vec![],
+        }
+    }
+}
+
+fn default_min_gap_ms() -> u64 {
+    500
+}
+
+#[derive(Deserialize)]
+pub struct RemoteConfig {
+    pub id: i32,
+    pub min_gap_ms: Option<u64>,
+    pub repeat: Option<u8>,
+    pub repeat_gap_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct SwitchConfig {
+    pub remote_id: i32,
+    pub switch_id: i32,
+    pub repeat: Option<u8>,
+    pub repeat_gap_ms: Option<u64>,
+    /// The longest this switch can stay on before
+    /// it is turned off regardless of the schedule
+    pub max_on_secs: Option<i64>,
+    /// Send commands even when the switch was already
+    /// commanded into that state
+    #[serde(default)]
+    pub always_send: bool,
 }
 
 #[derive(Deserialize)]
@@ -39,17 +397,168 @@ pub struct MqConfig {
     port: u16,
     login: String,
     password: String,
+    /// Where messages that can't be decoded or parsed are
+    /// republished, when missing they are logged and dropped
+    pub dead_letter_exchange: Option<ExchangeConfig>,
+    /// Where an event is published for every flip that is
+    /// sent, skipped or fails, when missing none are published.
+    /// Events are routed by key so this should be a topic exchange
+    pub events_exchange: Option<ExchangeConfig>,
+    #[serde(default = "default_vhost")]
+    pub vhost: String,
+    /// The channel flips are published on
+    #[serde(default = "default_publish_channel")]
+    pub publish_channel: u16,
+    /// The channel commands and reports are consumed on
+    #[serde(default = "default_listen_channel")]
+    pub listen_channel: u16,
+    /// Publish with delivery mode 2 so messages sitting in a
+    /// durable queue survive a broker restart
+    #[serde(default)]
+    pub persistent: bool,
+    #[serde(default = "default_exchange")]
+    pub exchange: ExchangeConfig,
+    /// The queue flips are published to, its routing keys
+    /// are the remote ids so `routing_key` is ignored
+    #[serde(default = "default_switches_queue")]
+    pub switches: QueueConfig,
+    #[serde(default = "default_refresh_queue")]
+    pub refresh: QueueConfig,
+    #[serde(default = "default_states_queue")]
+    pub states: QueueConfig,
+    #[serde(default = "default_heartbeats_queue")]
+    pub heartbeats: QueueConfig,
+    #[serde(default = "default_query_queue")]
+    pub query: QueueConfig,
+    /// The queue bound to the dead letter exchange so dead
+    /// letters are kept until someone looks at them, only
+    /// declared when `dead_letter_exchange` is set
+    #[serde(default = "default_dead_letter_queue")]
+    pub dead_letter_queue: QueueConfig,
+    /// Connecting over amqps isn't supported, see `TlsConfig`
+    pub tls: Option<TlsConfig>,
 }
 
-impl<'a> Into<amqp::Options> for &'a MqConfig {
-    fn into(self) -> amqp::Options {
-        amqp::Options {
+/// Settings for an amqps connection, not supported yet. The amqp
+/// client never checks the broker's certificate or hostname and
+/// can't present a client certificate, so connecting with it would
+/// send the login over a channel anyone in the middle can read.
+/// A `tls` section is refused at startup instead of being ignored
+#[derive(Deserialize)]
+pub struct TlsConfig {}
+
+impl MqConfig {
+    /// The options to open a session with
+    pub fn options(&self) -> Result<amqp::Options, error::Error> {
+        self.check_tls()?;
+        Ok(amqp::Options {
             host: self.host.clone(),
             port: self.port,
             login: self.login.clone(),
             password: self.password.clone(),
-            vhost: String::from("/"),
+            vhost: self.vhost.clone(),
             ..::std::default::Default::default()
+        })
+    }
+
+    /// Refuse a `tls` section, see `TlsConfig`
+    pub fn check_tls(&self) -> Result<(), error::Error> {
+        if self.tls.is_some() {
+            return Err(error::Error::other("TLS connections to the broker are not supported, the amqp client can't verify the broker's certificate"));
+        }
+        Ok(())
+    }
+
+    /// The delivery mode everything is published with
+    pub fn delivery_mode(&self) -> Option<u8> {
+        if self.persistent {
+            Some(2)
+        } else {
+            None
         }
     }
-}
\ No newline at end of file
+}
+
+/// Changing `durable` or `auto_delete` on an exchange or
+/// queue that already exists fails until it is deleted
+#[derive(Deserialize)]
+pub struct ExchangeConfig {
+    pub name: String,
+    #[serde(default = "default_exchange_kind")]
+    pub kind: String,
+    #[serde(default)]
+    pub durable: bool,
+    #[serde(default)]
+    pub auto_delete: bool,
+}
+
+#[derive(Deserialize)]
+pub struct QueueConfig {
+    pub name: String,
+    #[serde(default)]
+    pub routing_key: String,
+    #[serde(default)]
+    pub durable: bool,
+    #[serde(default)]
+    pub auto_delete: bool,
+}
+
+impl QueueConfig {
+    fn new(name: &str, routing_key: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            routing_key: routing_key.to_owned(),
+            durable: false,
+            auto_delete: false,
+        }
+    }
+}
+
+fn default_vhost() -> String {
+    String::from("/")
+}
+
+fn default_publish_channel() -> u16 {
+    1
+}
+
+fn default_listen_channel() -> u16 {
+    2
+}
+
+fn default_exchange_kind() -> String {
+    String::from("topic")
+}
+
+fn default_exchange() -> ExchangeConfig {
+    ExchangeConfig {
+        name: String::from("switches"),
+        kind: default_exchange_kind(),
+        durable: false,
+        auto_delete: false,
+    }
+}
+
+fn default_switches_queue() -> QueueConfig {
+    QueueConfig::new("switches", "")
+}
+
+fn default_refresh_queue() -> QueueConfig {
+    QueueConfig::new("refresh", "update")
+}
+
+fn default_states_queue() -> QueueConfig {
+    QueueConfig::new("states", "state")
+}
+
+fn default_heartbeats_queue() -> QueueConfig {
+    QueueConfig::new("heartbeats", "heartbeat")
+}
+
+fn default_query_queue() -> QueueConfig {
+    QueueConfig::new("query", "query")
+}
+
+fn default_dead_letter_queue() -> QueueConfig {
+    QueueConfig::new("dead_letter", "#")
+}
@@ -1,3 +1,6 @@
+use data::{Flip, SwitchState};
+use error::Error;
+use chrono::{DateTime, Utc};
 
 #[derive(Clone, Debug)]
 pub enum ChannelMessage {
@@ -6,8 +9,25 @@ pub enum ChannelMessage {
     FlipperOutOfDate,
     FlipperComplete,
     FlipperUpdated,
-    MqUpdateFlip,
-    Error(String),
+    FlipperDispatch(FlipCommand),
+    /// A flip the Flipper didn't pass on, with the reason
+    FlipperSkipped(FlipCommand, Outcome),
+    /// How many flips are left today and when the next is due
+    FlipperStatus(usize, Option<DateTime<Utc>>),
+    DispatcherEnqueue(FlipCommand),
+    DispatcherStateReport(StateReport),
+    DispatcherHeartbeat(i32, DateTime<Utc>),
+    DispatcherRecord(FlipCommand, Outcome),
+    FlipperPause,
+    FlipperResume,
+    FlipperProfile(Option<String>),
+    MqCommand(RemoteCommand),
+    MqStateReport(StateReport),
+    MqHeartbeat(i32, DateTime<Utc>),
+    /// The listener connected to (true) or lost (false) the broker
+    MqStatus(bool),
+    StatusPublish(Status),
+    Error(Fault),
     Stop,
     Tick,
 }
@@ -20,10 +40,309 @@ impl ::std::fmt::Display for ChannelMessage {
             ChannelMessage::FlipperOutOfDate => write!(f, "FL IN FlipperOutOfDate"),
             ChannelMessage::FlipperComplete => write!(f, "FL IN FlipperComplete"),
             ChannelMessage::FlipperUpdated => write!(f, "FL IN FlipperUpdated"),
-            ChannelMessage::MqUpdateFlip => write!(f, "MQ IN MqUpdateFlip"),
-            ChannelMessage::Error(msg) => write!(f, "?? IN MqError: {}", msg),
+            ChannelMessage::FlipperDispatch(cmd) => write!(f, "FL IN FlipperDispatch {}", cmd),
+            ChannelMessage::FlipperSkipped(cmd, outcome) => write!(f, "FL IN FlipperSkipped {} {}", cmd, outcome),
+            ChannelMessage::FlipperStatus(remaining, _) => write!(f, "FL IN FlipperStatus {} remaining", remaining),
+            ChannelMessage::StatusPublish(_) => write!(f, "ST OUT StatusPublish"),
+            ChannelMessage::DispatcherRecord(cmd, outcome) => write!(f, "DS OUT DispatcherRecord {} {}", cmd, outcome),
+            ChannelMessage::DispatcherEnqueue(cmd) => write!(f, "DS OUT DispatcherEnqueue {}", cmd),
+            ChannelMessage::DispatcherStateReport(report) => write!(f, "DS OUT DispatcherStateReport {}", report),
+            ChannelMessage::DispatcherHeartbeat(remote_id, _) => write!(f, "DS OUT DispatcherHeartbeat {}", remote_id),
+            ChannelMessage::FlipperPause => write!(f, "FL OUT FlipperPause"),
+            ChannelMessage::FlipperResume => write!(f, "FL OUT FlipperResume"),
+            ChannelMessage::FlipperProfile(profile) => write!(f, "FL OUT FlipperProfile {}", profile.as_ref().map(|p| p.as_str()).unwrap_or("(none)")),
+            ChannelMessage::MqCommand(cmd) => write!(f, "MQ IN MqCommand {}", cmd),
+            ChannelMessage::MqStateReport(report) => write!(f, "MQ IN MqStateReport {}", report),
+            ChannelMessage::MqHeartbeat(remote_id, _) => write!(f, "MQ IN MqHeartbeat {}", remote_id),
+            ChannelMessage::MqStatus(connected) => write!(f, "MQ IN MqStatus {}", if *connected { "connected" } else { "disconnected" }),
+            ChannelMessage::Error(fault) => write!(f, "?? IN Error {}", fault),
             ChannelMessage::Stop => write!(f, "?? OUT Stop"),
             ChannelMessage::Tick => write!(f, "CT IN Tick"),
         }
     }
-}
\ No newline at end of file
+}
+
+/// The threads the Supervisor looks after
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Worker {
+    Flipper,
+    Dispatcher,
+    Mq,
+    Counter,
+    Status,
+    Query,
+}
+
+impl ::std::fmt::Display for Worker {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match self {
+            Worker::Flipper => write!(f, "flipper"),
+            Worker::Dispatcher => write!(f, "dispatcher"),
+            Worker::Mq => write!(f, "mq"),
+            Worker::Counter => write!(f, "counter"),
+            Worker::Status => write!(f, "status"),
+            Worker::Query => write!(f, "query"),
+        }
+    }
+}
+
+/// How bad an error is, which decides what
+/// the Supervisor does about it
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Severity {
+    /// The worker carried on, the error is logged and counted
+    Recoverable,
+    /// The worker stopped and needs to be restarted
+    WorkerFatal,
+    /// The switcher can't keep running
+    Unrecoverable,
+}
+
+/// An error a worker ran into, reported to the Supervisor
+#[derive(Clone, Debug)]
+pub struct Fault {
+    pub worker: Worker,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Fault {
+    pub fn new(worker: Worker, severity: Severity, error: &Error) -> Self {
+        Self {
+            worker,
+            severity,
+            message: error.to_string(),
+        }
+    }
+}
+
+impl ::std::fmt::Display for Fault {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "{:?} in {}: {}", self.severity, self.worker, self.message)
+    }
+}
+
+/// A snapshot of what the switcher is doing, published
+/// periodically so monitoring can tell it is alive
+#[derive(Serialize, Clone, Debug)]
+pub struct Status {
+    pub at: DateTime<Utc>,
+    pub started: DateTime<Utc>,
+    pub uptime_secs: i64,
+    pub workers: Vec<WorkerStatus>,
+    pub flips_remaining: usize,
+    pub next_flip: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub mq_connected: bool,
+    /// Filled in just before publishing, `None` until then
+    pub db_connected: Option<bool>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct WorkerStatus {
+    pub worker: String,
+    pub state: WorkerState,
+    /// Restarts inside the Supervisor's restart window
+    pub restarts: usize,
+    /// Recoverable errors since the switcher started
+    pub errors: u64,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum WorkerState {
+    Running,
+    /// Stopped and waiting to be restarted
+    Stopped,
+    /// Stopped too many times inside the restart window,
+    /// it won't be started again
+    GivenUp,
+}
+
+/// A single command for a remote, on its way from the
+/// Flipper to the Dispatcher
+#[derive(Clone, Debug)]
+pub struct FlipCommand {
+    pub flip_id: Option<i32>,
+    /// When the schedule said to send it, `None` for
+    /// commands that didn't come from the schedule
+    pub scheduled: Option<DateTime<Utc>>,
+    pub reason: FlipReason,
+    pub remote_id: i32,
+    pub switch_id: i32,
+    pub direction: SwitchState,
+}
+
+impl<'a> From<&'a Flip> for FlipCommand {
+    fn from(flip: &'a Flip) -> Self {
+        Self {
+            flip_id: Some(flip.id),
+            scheduled: None,
+            reason: FlipReason::Schedule,
+            remote_id: flip.remote_id,
+            switch_id: flip.switch_id,
+            direction: flip.direction,
+        }
+    }
+}
+
+/// Why a command is being sent
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+pub enum FlipReason {
+    /// A flip from the day's schedule
+    Schedule,
+    /// A resend after a switch didn't report the commanded state
+    Mismatch,
+    /// A switch was on longer than its configured maximum
+    Cutoff,
+    /// A `flip-now` command from MQ
+    Manual,
+    /// Part of a scene applied with a `run-scene` command
+    Scene,
+}
+
+impl ::std::fmt::Display for FlipReason {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match self {
+            FlipReason::Schedule => write!(f, "schedule"),
+            FlipReason::Mismatch => write!(f, "mismatch"),
+            FlipReason::Cutoff => write!(f, "cutoff"),
+            FlipReason::Manual => write!(f, "manual"),
+            FlipReason::Scene => write!(f, "scene"),
+        }
+    }
+}
+
+impl ::std::fmt::Display for FlipCommand {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "{}:{} {:?}", self.remote_id, self.switch_id, self.direction)
+    }
+}
+
+/// A command published to the refresh queue, the JSON
+/// form carries its name in the `command` field
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum RemoteCommand {
+    /// Reload today's flips from the database
+    Refresh,
+    /// Send a switch into a state right away
+    FlipNow { remote_id: i32, switch_id: i32, state: SwitchState },
+    /// Apply every switch state in a configured scene
+    RunScene { scene: String },
+    /// Stop sending scheduled flips, flips that come due
+    /// while paused are skipped rather than sent later
+    Pause,
+    Resume,
+    /// Switch schedule profiles, `None` only uses the
+    /// flips without a profile
+    SetProfile { profile: Option<String> },
+    /// Log what the switcher is currently doing
+    Status,
+}
+
+impl ::std::fmt::Display for RemoteCommand {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match self {
+            RemoteCommand::Refresh => write!(f, "refresh"),
+            RemoteCommand::FlipNow { remote_id, switch_id, state } => write!(f, "flip-now {}:{} {:?}", remote_id, switch_id, state),
+            RemoteCommand::RunScene { scene } => write!(f, "run-scene {}", scene),
+            RemoteCommand::Pause => write!(f, "pause"),
+            RemoteCommand::Resume => write!(f, "resume"),
+            RemoteCommand::SetProfile { profile } => write!(f, "set-profile {}", profile.as_ref().map(|p| p.as_str()).unwrap_or("(none)")),
+            RemoteCommand::Status => write!(f, "status"),
+        }
+    }
+}
+
+/// What happened to a command, kept in the history as
+/// `kind` or `kind: detail` and published as an event
+#[derive(Clone, PartialEq, Debug)]
+pub enum Outcome {
+    Sent,
+    /// Left out on purpose, with why
+    Skipped(String),
+    /// The transport couldn't send it
+    Failed(String),
+    /// It would have broken an interlock
+    Refused(String),
+    /// It was held for an offline remote for too long
+    Expired,
+}
+
+impl Outcome {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Outcome::Sent => "sent",
+            Outcome::Skipped(_) => "skipped",
+            Outcome::Failed(_) => "failed",
+            Outcome::Refused(_) => "refused",
+            Outcome::Expired => "expired",
+        }
+    }
+
+    pub fn detail(&self) -> Option<&str> {
+        match self {
+            Outcome::Skipped(detail) | Outcome::Failed(detail) | Outcome::Refused(detail) => Some(detail.as_str()),
+            Outcome::Sent | Outcome::Expired => None,
+        }
+    }
+}
+
+impl ::std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match self.detail() {
+            Some(detail) => write!(f, "{}: {}", self.kind(), detail),
+            None => write!(f, "{}", self.kind()),
+        }
+    }
+}
+
+/// What happened to a command, published for
+/// other services to follow along
+#[derive(Serialize, Debug)]
+pub struct FlipEvent {
+    pub flip_id: Option<i32>,
+    pub remote_id: i32,
+    pub switch_id: i32,
+    pub state: SwitchState,
+    pub scheduled: Option<DateTime<Utc>>,
+    pub at: DateTime<Utc>,
+    pub reason: FlipReason,
+    /// `sent`, `skipped`, `failed`, `refused` or `expired`
+    pub outcome: &'static str,
+    /// Why it was skipped, failed or refused
+    pub detail: Option<String>,
+}
+
+impl FlipEvent {
+    pub fn new(cmd: &FlipCommand, outcome: &Outcome, at: DateTime<Utc>) -> Self {
+        Self {
+            flip_id: cmd.flip_id,
+            remote_id: cmd.remote_id,
+            switch_id: cmd.switch_id,
+            state: cmd.direction,
+            scheduled: cmd.scheduled,
+            at,
+            reason: cmd.reason,
+            outcome: outcome.kind(),
+            detail: outcome.detail().map(|d| d.to_owned()),
+        }
+    }
+}
+
+/// A remote telling us what state one of its
+/// switches is actually in
+#[derive(Clone, Debug)]
+pub struct StateReport {
+    pub remote_id: i32,
+    pub switch_id: i32,
+    pub state: SwitchState,
+    pub at: DateTime<Utc>,
+}
+
+impl ::std::fmt::Display for StateReport {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "{}:{} {:?} at {}", self.remote_id, self.switch_id, self.state, self.at)
+    }
+}
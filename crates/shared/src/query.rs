@@ -0,0 +1,100 @@
+use data::{SwitchState, TimeKind, get_last_override};
+use error::Error;
+use schedule::{plan, resolve_local, schedule_date};
+
+use super::CONFIG;
+
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use chrono_tz::Tz;
+
+/// How many schedule days to look back for the last
+/// flip before giving up on a switch
+const LOOKBACK_DAYS: i64 = 7;
+
+/// The state a switch is expected to be in at
+/// a moment and what put it in that state
+#[derive(Serialize, Debug)]
+pub struct Explanation {
+    pub remote_id: i32,
+    pub switch_id: i32,
+    pub at: String,
+    pub state: Option<SwitchState>,
+    pub source: Source,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Source {
+    /// A scheduled flip
+    Flip { flip_id: i32, kind: TimeKind, at: String },
+    /// A command sent outside of the schedule
+    Override { reason: String, at: String },
+    /// Nothing has touched this switch recently
+    Unknown,
+}
+
+impl ::std::fmt::Display for Explanation {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match self.state {
+            Some(state) => write!(f, "{}:{} is expected to be {:?} at {}", self.remote_id, self.switch_id, state, self.at)?,
+            None => write!(f, "{}:{} is in an unknown state at {}", self.remote_id, self.switch_id, self.at)?,
+        }
+        match &self.source {
+            Source::Flip { flip_id, kind, at } => write!(f, "\nset by flip {} ({:?}) at {}", flip_id, kind, at),
+            Source::Override { reason, at } => write!(f, "\nset by a {} command at {}", reason, at),
+            Source::Unknown => write!(f, "\nno flip in the last {} days", LOOKBACK_DAYS),
+        }
+    }
+}
+
+/// A wall clock time in the home timezone resolved the same
+/// way flips are, a time that is skipped is only an error when
+/// the daylight saving policy is to skip it
+pub fn home_time(local: NaiveDateTime) -> Result<DateTime<Tz>, Error> {
+    resolve_local(&local, &CONFIG.timezone(), &CONFIG.dst)
+        .ok_or_else(|| Error::Other(format!("{} does not exist in {}", local, CONFIG.timezone())))
+}
+
+/// Replay the schedule for `profile` to work out what state a
+/// switch should be in at `at`, a command sent outside of the
+/// schedule after the last scheduled flip wins over it
+pub fn expected_state(remote_id: i32, switch_id: i32, at: DateTime<Tz>, profile: Option<&str>) -> Result<Explanation, Error> {
+    let day = schedule_date(at.naive_local());
+    let mut last = None;
+    for back in 0..LOOKBACK_DAYS {
+        let planned = plan(day - Duration::days(back), profile)?;
+        last = planned.into_iter()
+            .rev()
+            .find(|p| p.flip.remote_id == remote_id && p.flip.switch_id == switch_id && p.at <= at);
+        if last.is_some() {
+            break;
+        }
+    }
+    let utc = at.with_timezone(&Utc);
+    let mut state = None;
+    let mut source = Source::Unknown;
+    let mut since = utc - Duration::days(LOOKBACK_DAYS);
+    if let Some(p) = last {
+        state = Some(p.flip.direction);
+        since = p.at.with_timezone(&Utc);
+        source = Source::Flip {
+            flip_id: p.flip.id,
+            kind: p.flip.time.kind,
+            at: p.at.to_rfc3339(),
+        };
+    }
+    if let Some((direction, sent, reason)) = get_last_override(remote_id, switch_id, since, utc)? {
+        state = Some(direction);
+        source = Source::Override {
+            reason,
+            at: sent.with_timezone(&at.timezone()).to_rfc3339(),
+        };
+    }
+    Ok(Explanation {
+        remote_id,
+        switch_id,
+        at: at.to_rfc3339(),
+        state,
+        source,
+    })
+}
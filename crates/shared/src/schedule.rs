@@ -0,0 +1,305 @@
+use data::{Flip, SwitchState, Time, TimeKind, get_flips};
+use error::Error;
+use solar::sun_times;
+use super::{CONFIG, Ambiguous, DstConfig, Nonexistent};
+
+use chrono::{DateTime, Duration, LocalResult, NaiveDate, NaiveDateTime, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+/// Something worth pointing out about a day's schedule
+#[derive(Debug)]
+pub enum Issue {
+    /// Flips for the same switch at the same minute that
+    /// disagree, only `winner` will be sent
+    Conflict { winner: i32, losers: Vec<i32> },
+    /// A flip that puts a switch into the state the
+    /// previous flip already left it in
+    Redundant { flip: i32, previous: i32 },
+    /// A flip with a time that never happens
+    Unreachable { flip: i32 },
+}
+
+impl ::std::fmt::Display for Issue {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match self {
+            Issue::Conflict { winner, losers } => write!(f, "flip {} conflicts with {:?} and wins", winner, losers),
+            Issue::Redundant { flip, previous } => write!(f, "flip {} repeats the state set by flip {}", flip, previous),
+            Issue::Unreachable { flip } => write!(f, "flip {} has a time that never happens", flip),
+        }
+    }
+}
+
+fn day_start_minute() -> i32 {
+    let start = CONFIG.day_start();
+    (start.hour() * 60 + start.minute()) as i32
+}
+
+/// The schedule day a moment belongs to, a schedule day runs from
+/// the configured day start until the same time the next day so
+/// with a 04:00 start, 01:00 Saturday is still part of Friday
+pub fn schedule_date(now: NaiveDateTime) -> NaiveDate {
+    (now - Duration::minutes(day_start_minute() as i64)).date()
+}
+
+/// The number of minutes after the start of the schedule
+/// day this time falls on
+pub fn day_minute(time: &Time) -> i32 {
+    (time.minute_of_day() - day_start_minute() + 24 * 60) % (24 * 60)
+}
+
+/// The calendar date and time a flip's time lands on for a
+/// schedule day, times before the day start roll over to the
+/// next calendar day
+pub fn resolve_time(time: &Time, day: NaiveDate) -> NaiveDateTime {
+    let minute = time.minute_of_day();
+    let date = if minute < day_start_minute() {
+        day.succ()
+    } else {
+        day
+    };
+    date.and_hms((minute / 60) as u32, (minute % 60) as u32, 0)
+}
+
+/// The instant a flip's time lands on for a schedule day in
+/// `tz`, returns `None` when the time is skipped by a daylight
+/// saving change and the policy is to skip it
+pub fn resolve_instant<Tz: TimeZone>(time: &Time, day: NaiveDate, tz: &Tz, policy: &DstConfig) -> Option<DateTime<Tz>> {
+    resolve_local(&resolve_time(time, day), tz, policy)
+}
+
+/// A wall clock time in `tz` following the daylight
+/// saving policy, see `resolve_instant`
+pub fn resolve_local<Tz: TimeZone>(local: &NaiveDateTime, tz: &Tz, policy: &DstConfig) -> Option<DateTime<Tz>> {
+    match tz.from_local_datetime(local) {
+        LocalResult::Single(dt) => Some(dt),
+        LocalResult::Ambiguous(earliest, latest) => match policy.ambiguous {
+            Ambiguous::Earliest => Some(earliest),
+            Ambiguous::Latest => Some(latest),
+        },
+        LocalResult::None => match policy.nonexistent {
+            Nonexistent::Skip => None,
+            Nonexistent::ShiftForward => first_after_gap(local, tz),
+        },
+    }
+}
+
+/// Walk forward a minute at a time until the local clock
+/// exists again, no real gap is longer than a few hours
+fn first_after_gap<Tz: TimeZone>(local: &NaiveDateTime, tz: &Tz) -> Option<DateTime<Tz>> {
+    (1..=24 * 60).filter_map(|m| {
+        tz.from_local_datetime(&(*local + Duration::minutes(m))).earliest()
+    }).next()
+}
+
+/// The time a flip lands on for a particular day, solar times
+/// are worked out for that day from the configured location
+/// instead of using the times last saved by the daily updater.
+/// Without a location the saved times are kept
+pub fn time_on(time: &Time, day: NaiveDate) -> Time {
+    let minute = match time.kind {
+        TimeKind::Custom => return Time::at_minute(time.minute_of_day(), time.kind, time.day_of_week),
+        TimeKind::Noon => 12 * 60,
+        TimeKind::Midnight => 0,
+        TimeKind::Dawn | TimeKind::Sunrise | TimeKind::Sunset | TimeKind::Dusk => {
+            let times = match (CONFIG.latitude, CONFIG.longitude) {
+                (Some(latitude), Some(longitude)) => sun_times(day, latitude, longitude),
+                _ => None,
+            };
+            let (rise, set) = match times {
+                Some(times) => times,
+                None => return Time::at_minute(time.minute_of_day(), time.kind, time.day_of_week),
+            };
+            let tz = CONFIG.timezone();
+            let local_minute = |dt: DateTime<Utc>| {
+                let local = dt.with_timezone(&tz);
+                (local.hour() * 60 + local.minute()) as i32
+            };
+            match time.kind {
+                TimeKind::Dawn => local_minute(rise) - 60,
+                TimeKind::Sunrise => local_minute(rise),
+                TimeKind::Sunset => local_minute(set),
+                _ => local_minute(set) - 60,
+            }
+        },
+    };
+    Time::at_minute(minute, time.kind, time.day_of_week)
+}
+
+/// The schedule profile in use right now, changed by the
+/// Flipper on a `set-profile` command and read by anything
+/// else that replays the schedule
+pub type SharedProfile = Arc<RwLock<Option<String>>>;
+
+/// A flip along with the moment it will be sent
+pub struct PlannedFlip {
+    pub at: DateTime<Tz>,
+    pub flip: Flip,
+}
+
+/// Every flip that will be sent on a schedule day in the order
+/// they will be sent, with solar times worked out for that day.
+/// Flips tagged with a profile other than `profile` are left out
+pub fn plan(day: NaiveDate, profile: Option<&str>) -> Result<Vec<PlannedFlip>, Error> {
+    let mut flips = get_flips(day, profile)?;
+    for flip in flips.iter_mut() {
+        flip.time = time_on(&flip.time, day);
+    }
+    let tz = CONFIG.timezone();
+    let mut ret: Vec<PlannedFlip> = resolve(flips).into_iter().filter_map(|flip| {
+        resolve_instant(&flip.time, day, &tz, &CONFIG.dst).map(|at| PlannedFlip { at, flip })
+    }).collect();
+    ret.sort_by_key(|p| p.at);
+    Ok(ret)
+}
+
+/// The deterministic order flips at the same minute for the
+/// same switch are resolved in, the first one wins. Higher
+/// priority goes first, then off before on so a tie errs on
+/// the side of a switch being off, then the newest flip
+fn precedence(lhs: &Flip, rhs: &Flip) -> Ordering {
+    rhs.priority.cmp(&lhs.priority)
+        .then_with(|| lhs.direction.for_db().cmp(&rhs.direction.for_db()))
+        .then_with(|| rhs.id.cmp(&lhs.id))
+}
+
+fn key(flip: &Flip) -> (i32, i32, i32) {
+    (flip.remote_id, flip.switch_id, day_minute(&flip.time))
+}
+
+/// Sort a day's flips by time and drop all but the winner
+/// of each set of flips for the same switch at the same minute
+pub fn resolve(flips: Vec<Flip>) -> Vec<Flip> {
+    resolve_losers(flips).0
+}
+
+/// Like `resolve` but also returns the flips that were dropped
+/// along with the id of the flip that won over each of them
+pub fn resolve_losers(mut flips: Vec<Flip>) -> (Vec<Flip>, Vec<(Flip, i32)>) {
+    flips.retain(|f| f.time.is_valid());
+    flips.sort_by(|lhs, rhs| key(lhs).cmp(&key(rhs)).then_with(|| precedence(lhs, rhs)));
+    let mut winners: Vec<Flip> = Vec::with_capacity(flips.len());
+    let mut losers = vec![];
+    for flip in flips {
+        match winners.last() {
+            Some(winner) if key(winner) == key(&flip) => {
+                let id = winner.id;
+                losers.push((flip, id));
+            },
+            _ => winners.push(flip),
+        }
+    }
+    winners.sort_by_key(|f| (day_minute(&f.time), f.remote_id, f.switch_id));
+    (winners, losers)
+}
+
+/// Report the conflicts, redundant flips and flips that will
+/// never fire in a day's schedule
+pub fn lint(flips: &[Flip]) -> Vec<Issue> {
+    let mut ret = vec![];
+    let mut groups: HashMap<(i32, i32, i32), Vec<&Flip>> = HashMap::new();
+    for flip in flips {
+        if !flip.time.is_valid() {
+            ret.push(Issue::Unreachable { flip: flip.id });
+            continue;
+        }
+        groups.entry(key(flip)).or_default().push(flip);
+    }
+    let mut winners = vec![];
+    let mut keys: Vec<&(i32, i32, i32)> = groups.keys().collect();
+    keys.sort();
+    for k in keys {
+        let mut group = groups[k].clone();
+        group.sort_by(|lhs, rhs| precedence(lhs, rhs));
+        let winner = group[0];
+        let losers: Vec<i32> = group[1..].iter()
+            .filter(|f| f.direction != winner.direction)
+            .map(|f| f.id)
+            .collect();
+        for dupe in group[1..].iter().filter(|f| f.direction == winner.direction) {
+            ret.push(Issue::Redundant { flip: dupe.id, previous: winner.id });
+        }
+        if !losers.is_empty() {
+            ret.push(Issue::Conflict { winner: winner.id, losers });
+        }
+        winners.push(winner);
+    }
+    let mut last: HashMap<(i32, i32), (i32, SwitchState)> = HashMap::new();
+    for flip in winners {
+        let switch = (flip.remote_id, flip.switch_id);
+        if let Some(&(previous, state)) = last.get(&switch) {
+            if state == flip.direction {
+                ret.push(Issue::Redundant { flip: flip.id, previous });
+            }
+        }
+        last.insert(switch, (flip.id, flip.direction));
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::America::Chicago;
+
+    /// The flip time for a wall clock time along with the
+    /// schedule day it belongs to, whatever the day start is
+    fn flip_at(local: NaiveDateTime) -> (Time, NaiveDate) {
+        let minute = (local.hour() * 60 + local.minute()) as i32;
+        (Time::at_minute(minute, TimeKind::Custom, 0x7f), schedule_date(local))
+    }
+
+    fn policy(nonexistent: Nonexistent, ambiguous: Ambiguous) -> DstConfig {
+        DstConfig { nonexistent, ambiguous }
+    }
+
+    fn utc(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        Utc.ymd(y, m, d).and_hms(h, min, 0)
+    }
+
+    #[test]
+    fn spring_forward_skip() {
+        let (time, day) = flip_at(NaiveDate::from_ymd(2018, 3, 11).and_hms(2, 30, 0));
+        let at = resolve_instant(&time, day, &Chicago, &policy(Nonexistent::Skip, Ambiguous::Earliest));
+        assert!(at.is_none());
+    }
+
+    #[test]
+    fn spring_forward_shift() {
+        let (time, day) = flip_at(NaiveDate::from_ymd(2018, 3, 11).and_hms(2, 30, 0));
+        let at = resolve_instant(&time, day, &Chicago, &policy(Nonexistent::ShiftForward, Ambiguous::Earliest))
+            .expect("shifted time");
+        assert_eq!(at.with_timezone(&Utc), utc(2018, 3, 11, 8, 0));
+        assert_eq!(at.naive_local(), NaiveDate::from_ymd(2018, 3, 11).and_hms(3, 0, 0));
+    }
+
+    #[test]
+    fn fall_back_earliest() {
+        let (time, day) = flip_at(NaiveDate::from_ymd(2018, 11, 4).and_hms(1, 30, 0));
+        let at = resolve_instant(&time, day, &Chicago, &policy(Nonexistent::Skip, Ambiguous::Earliest))
+            .expect("earliest time");
+        assert_eq!(at.with_timezone(&Utc), utc(2018, 11, 4, 6, 30));
+    }
+
+    #[test]
+    fn fall_back_latest() {
+        let (time, day) = flip_at(NaiveDate::from_ymd(2018, 11, 4).and_hms(1, 30, 0));
+        let at = resolve_instant(&time, day, &Chicago, &policy(Nonexistent::Skip, Ambiguous::Latest))
+            .expect("latest time");
+        assert_eq!(at.with_timezone(&Utc), utc(2018, 11, 4, 7, 30));
+    }
+
+    #[test]
+    fn ordinary_time_ignores_policy() {
+        let (time, day) = flip_at(NaiveDate::from_ymd(2018, 11, 4).and_hms(3, 30, 0));
+        for p in &[policy(Nonexistent::Skip, Ambiguous::Earliest), policy(Nonexistent::ShiftForward, Ambiguous::Latest)] {
+            let at = resolve_instant(&time, day, &Chicago, p).expect("single time");
+            assert_eq!(at.with_timezone(&Utc), utc(2018, 11, 4, 9, 30));
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc};
+
+/// Julian day of 2000-01-01 12:00 UTC
+const J2000: f64 = 2_451_545.0;
+
+/// Work out sunrise and sunset for a date at a location using
+/// the sunrise equation, this is accurate to within a minute or
+/// two which is plenty for turning lights on. Longitude is
+/// positive to the east. Returns `None` when the sun doesn't
+/// rise or set that day
+pub fn sun_times(date: NaiveDate, latitude: f64, longitude: f64) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let n = (date - NaiveDate::from_ymd(2000, 1, 1)).num_days() as f64;
+    let mean_solar_noon = n - longitude / 360.0;
+    let anomaly = (357.5291 + 0.985_600_28 * mean_solar_noon) % 360.0;
+    let m = anomaly.to_radians();
+    let center = 1.9148 * m.sin() + 0.02 * (2.0 * m).sin() + 0.0003 * (3.0 * m).sin();
+    let ecliptic = ((anomaly + center + 180.0 + 102.9372) % 360.0).to_radians();
+    let transit = J2000 + mean_solar_noon + 0.0053 * m.sin() - 0.0069 * (2.0 * ecliptic).sin();
+    let declination = (ecliptic.sin() * 23.44_f64.to_radians().sin()).asin();
+    let lat = latitude.to_radians();
+    let cos_hour_angle = ((-0.833_f64).to_radians().sin() - lat.sin() * declination.sin())
+        / (lat.cos() * declination.cos());
+    if cos_hour_angle.abs() > 1.0 {
+        return None;
+    }
+    let hour_angle = cos_hour_angle.acos().to_degrees();
+    let rise = from_julian(transit - hour_angle / 360.0);
+    let set = from_julian(transit + hour_angle / 360.0);
+    Some((rise, set))
+}
+
+fn from_julian(day: f64) -> DateTime<Utc> {
+    let noon = Utc.ymd(2000, 1, 1).and_hms(12, 0, 0);
+    noon + Duration::seconds(((day - J2000) * 86_400.0).round() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Check a worked out time is within two minutes of a
+    /// published one, given as hours and minutes in UTC
+    fn near(actual: DateTime<Utc>, date: NaiveDate, h: u32, m: u32) {
+        let expected = Utc.from_utc_date(&date).and_hms(h, m, 0);
+        let off = (actual - expected).num_seconds().abs();
+        assert!(off <= 120, "{} is {}s from {}", actual, off, expected);
+    }
+
+    #[test]
+    fn london_midsummer() {
+        let date = NaiveDate::from_ymd(2018, 6, 21);
+        let (rise, set) = sun_times(date, 51.5074, -0.1278).expect("sun rises in london");
+        near(rise, date, 3, 43);
+        near(set, date, 20, 21);
+    }
+
+    #[test]
+    fn reykjavik_midwinter() {
+        let date = NaiveDate::from_ymd(2018, 12, 21);
+        let (rise, set) = sun_times(date, 64.1466, -21.9426).expect("sun rises in reykjavik");
+        near(rise, date, 11, 22);
+        near(set, date, 15, 29);
+    }
+
+    #[test]
+    fn reykjavik_sets_after_midnight_in_summer() {
+        let date = NaiveDate::from_ymd(2018, 6, 21);
+        let (rise, set) = sun_times(date, 64.1466, -21.9426).expect("sun sets in reykjavik");
+        near(rise, date, 2, 55);
+        near(set, date.succ(), 0, 4);
+    }
+
+    #[test]
+    fn no_sunrise_or_sunset_in_the_arctic() {
+        assert!(sun_times(NaiveDate::from_ymd(2018, 6, 21), 69.6492, 18.9553).is_none());
+        assert!(sun_times(NaiveDate::from_ymd(2018, 12, 21), 69.6492, 18.9553).is_none());
+    }
+}
@@ -0,0 +1,29 @@
+-- Tables used by robohome_switcher beyond the ones
+-- shared with the web app
+
+CREATE TABLE IF NOT EXISTS "SwitchOnTimes" (
+    "RemoteId" INTEGER NOT NULL,
+    "SwitchId" INTEGER NOT NULL,
+    "OnSince" TIMESTAMP WITH TIME ZONE NOT NULL,
+    PRIMARY KEY ("RemoteId", "SwitchId")
+);
+
+-- Flips that land on the same minute for the same switch
+-- are resolved by priority. PendingFlips belongs to the web
+-- app so the switcher reads this by joining "Flips" on "Id"
+ALTER TABLE "Flips" ADD COLUMN IF NOT EXISTS "Priority" INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE IF NOT EXISTS "FlipHistory" (
+    "Id" SERIAL PRIMARY KEY,
+    "FlipId" INTEGER,
+    "RemoteId" INTEGER NOT NULL,
+    "SwitchId" INTEGER NOT NULL,
+    "Direction" INTEGER NOT NULL,
+    "Reason" TEXT NOT NULL,
+    "Outcome" TEXT NOT NULL,
+    "At" TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+-- Flips tagged with a profile are only sent while that
+-- profile is active, read from "Flips" like "Priority"
+ALTER TABLE "Flips" ADD COLUMN IF NOT EXISTS "Profile" TEXT;
@@ -0,0 +1,111 @@
+use super::Error;
+
+use chrono::{NaiveDate, NaiveDateTime};
+
+/// What the switcher was asked to do on the command line
+pub enum Command {
+    /// Run the switcher as normal
+    Run,
+    /// Run the schedule against a virtual clock
+    Simulate(SimOptions),
+    /// Print the flips that will be sent on a date
+    Schedule { date: NaiveDate, json: bool },
+    /// Print the state a switch should be in at a time,
+    /// now when no time is provided
+    Query { remote_id: i32, switch_id: i32, at: Option<NaiveDateTime>, json: bool },
+}
+
+pub struct SimOptions {
+    /// The first schedule day to simulate
+    pub from: NaiveDate,
+    /// How many schedule days to simulate
+    pub days: i64,
+    /// How many times faster than real time to run, when
+    /// missing the clock jumps straight to each event
+    pub speed: Option<u32>,
+}
+
+/// Parse the arguments after the program name, `today`
+/// is used when no date is provided
+pub fn parse(args: Vec<String>, today: NaiveDate) -> Result<Command, Error> {
+    let mut args = args.into_iter();
+    let cmd = match args.next() {
+        Some(cmd) => cmd,
+        None => return Ok(Command::Run),
+    };
+    match cmd.as_str() {
+        "run" => Ok(Command::Run),
+        "simulate" => {
+            let mut opts = SimOptions {
+                from: today,
+                days: 1,
+                speed: None,
+            };
+            while let Some(flag) = args.next() {
+                let value = args.next().ok_or_else(|| Error::Other(format!("{} requires a value", flag)))?;
+                match flag.as_str() {
+                    "--from" => opts.from = parse_date(&value)?,
+                    "--days" => opts.days = parse_num(&flag, &value)?,
+                    "--speed" => opts.speed = Some(parse_num(&flag, &value)?),
+                    _ => return Err(Error::Other(format!("Unknown option {}", flag))),
+                }
+            }
+            Ok(Command::Simulate(opts))
+        },
+        "schedule" => {
+            let mut date = today;
+            let mut json = false;
+            while let Some(flag) = args.next() {
+                match flag.as_str() {
+                    "--json" => json = true,
+                    "--date" => {
+                        let value = args.next().ok_or_else(|| Error::Other(format!("{} requires a value", flag)))?;
+                        date = parse_date(&value)?;
+                    },
+                    _ => return Err(Error::Other(format!("Unknown option {}", flag))),
+                }
+            }
+            Ok(Command::Schedule { date, json })
+        },
+        "query" => {
+            let mut remote_id = None;
+            let mut switch_id = None;
+            let mut at = None;
+            let mut json = false;
+            while let Some(flag) = args.next() {
+                if flag == "--json" {
+                    json = true;
+                    continue;
+                }
+                let value = args.next().ok_or_else(|| Error::Other(format!("{} requires a value", flag)))?;
+                match flag.as_str() {
+                    "--remote" => remote_id = Some(parse_num(&flag, &value)?),
+                    "--switch" => switch_id = Some(parse_num(&flag, &value)?),
+                    "--at" => at = Some(parse_date_time(&value)?),
+                    _ => return Err(Error::Other(format!("Unknown option {}", flag))),
+                }
+            }
+            Ok(Command::Query {
+                remote_id: remote_id.ok_or_else(|| Error::other("query requires --remote"))?,
+                switch_id: switch_id.ok_or_else(|| Error::other("query requires --switch"))?,
+                at,
+                json,
+            })
+        },
+        _ => Err(Error::Other(format!("Unknown command {}", cmd))),
+    }
+}
+
+fn parse_date(value: &str) -> Result<NaiveDate, Error> {
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|e| Error::Other(format!("Invalid date {}, expected YYYY-MM-DD\n{}", value, e)))
+}
+
+fn parse_date_time(value: &str) -> Result<NaiveDateTime, Error> {
+    NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M")
+        .map_err(|e| Error::Other(format!("Invalid time {}, expected \"YYYY-MM-DD HH:MM\"\n{}", value, e)))
+}
+
+fn parse_num<T: ::std::str::FromStr>(flag: &str, value: &str) -> Result<T, Error> {
+    value.parse().map_err(|_| Error::Other(format!("Invalid number {} for {}", value, flag)))
+}
@@ -1,24 +1,26 @@
 use super::{ChannelMessage, Error,};
+use robohome_shared::clock::SharedClock;
 use std::{
     sync::mpsc::Sender,
     time::Duration,
-    thread::sleep,
 };
 pub struct Counter {
     sender: Sender<ChannelMessage>,
+    clock: SharedClock,
 }
 
 impl Counter {
-    pub fn new(sender: Sender<ChannelMessage>) -> Self {
+    pub fn new(sender: Sender<ChannelMessage>, clock: SharedClock) -> Self {
         Self {
             sender,
+            clock,
         }
     }
     pub fn run(self) -> Result<(), Error> {
         loop {
             self.sender.send(ChannelMessage::Tick)?;
             let sleep_for = Duration::from_millis(60 * 1000 as u64);
-            sleep(sleep_for);
+            self.clock.sleep(sleep_for);
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,956 @@
+use super::{ChannelMessage, Error, CONFIG};
+use interlock::{self, Violation};
+use transport::Transport;
+use data::{SwitchState, get_on_times, save_on_time, record_history};
+use robohome_shared::{
+    Config, DispatchConfig,
+    clock::SharedClock,
+    message::{FlipCommand, FlipEvent, FlipReason, Outcome, StateReport},
+};
+use presence::Presence;
+use state::SwitchTracker;
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    mem,
+    sync::{Arc, Mutex, mpsc::{Receiver, RecvError, RecvTimeoutError, channel}},
+    time::Duration as StdDuration,
+};
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+
+/// Owns an outgoing queue for each remote, making sure
+/// that commands for a single remote go out in the order
+/// they arrived and no closer together than that remote's
+/// configured minimum gap
+pub struct Dispatcher {
+    queues: HashMap<i32, RemoteQueue>,
+    tracker: SwitchTracker,
+    presence: Presence,
+    held: HashMap<(i32, i32), Held>,
+    cutoffs: HashSet<(i32, i32)>,
+    clock: SharedClock,
+    transport: Box<dyn Transport>,
+    config: &'static Config,
+    /// Save on times and history to the database
+    persist: bool,
+    /// Treat every remote as online and skip feedback checks
+    simulated: bool,
+    rx: Receiver<ChannelMessage>,
+    /// Where everything above is put back when this
+    /// Dispatcher stops, so a restart carries on from it
+    saved: Option<SavedDispatch>,
+}
+
+/// What a Dispatcher leaves behind when it stops, including
+/// its channel so messages sent while it is being restarted
+/// are still there for the next one
+pub struct DispatchState {
+    rx: Receiver<ChannelMessage>,
+    queues: HashMap<i32, RemoteQueue>,
+    tracker: SwitchTracker,
+    presence: Presence,
+    held: HashMap<(i32, i32), Held>,
+    cutoffs: HashSet<(i32, i32)>,
+}
+
+/// Kept by the Supervisor across Dispatcher restarts, empty
+/// while a Dispatcher is running
+pub type SavedDispatch = Arc<Mutex<Option<DispatchState>>>;
+
+impl DispatchState {
+    pub fn saved(rx: Receiver<ChannelMessage>) -> SavedDispatch {
+        Arc::new(Mutex::new(Some(Self {
+            rx,
+            queues: HashMap::new(),
+            tracker: SwitchTracker::new(),
+            presence: Presence::new(&CONFIG.presence),
+            held: HashMap::new(),
+            cutoffs: HashSet::new(),
+        })))
+    }
+}
+
+/// The latest command for a switch on a remote that
+/// was offline when it came up in the queue
+struct Held {
+    cmd: FlipCommand,
+    since: DateTime<Utc>,
+}
+
+struct RemoteQueue {
+    pending: VecDeque<Outgoing>,
+    last_sent: DateTime<Utc>,
+    gap: Duration,
+}
+
+/// A command waiting in a remote's queue, `repeat` is the
+/// number of copies already sent
+struct Outgoing {
+    cmd: FlipCommand,
+    repeat: u8,
+    not_before: DateTime<Utc>,
+    deferred: u8,
+    /// How many times sending this copy has failed
+    failures: u8,
+}
+
+/// How many times a command can wait on another command
+/// to satisfy an interlock before it is refused
+const MAX_DEFERRALS: u8 = 10;
+/// How many times sending a command is tried before
+/// it is recorded as failed, the wait between tries
+/// doubles from `MIN_RETRY_MS` up to `MAX_RETRY_MS`
+const SEND_ATTEMPTS: u8 = 5;
+const MIN_RETRY_MS: i64 = 500;
+const MAX_RETRY_MS: i64 = 30 * 1000;
+
+impl Outgoing {
+    fn new(cmd: FlipCommand) -> Self {
+        Self {
+            cmd,
+            repeat: 0,
+            not_before: Utc.timestamp(0, 0),
+            deferred: 0,
+            failures: 0,
+        }
+    }
+
+    /// The next copy of this command if the switch is
+    /// configured for more retransmissions
+    fn next_repeat(&self, now: DateTime<Utc>, config: &DispatchConfig) -> Option<Self> {
+        let (remote_id, switch_id) = (self.cmd.remote_id, self.cmd.switch_id);
+        if self.repeat >= config.repeat(remote_id, switch_id) {
+            return None;
+        }
+        let gap = Duration::milliseconds(config.repeat_gap_ms(remote_id, switch_id) as i64);
+        Some(Self {
+            cmd: self.cmd.clone(),
+            repeat: self.repeat + 1,
+            not_before: now + gap,
+            deferred: 0,
+            failures: 0,
+        })
+    }
+
+    /// How long to wait before trying again after a failed send
+    fn retry_delay(&self) -> Duration {
+        let ms = MIN_RETRY_MS.saturating_mul(1 << self.failures.min(16));
+        Duration::milliseconds(ms.min(MAX_RETRY_MS))
+    }
+}
+
+impl RemoteQueue {
+    fn new(remote_id: i32, config: &DispatchConfig) -> Self {
+        Self {
+            pending: VecDeque::new(),
+            last_sent: Utc.timestamp(0, 0),
+            gap: Duration::milliseconds(config.min_gap_ms(remote_id) as i64),
+        }
+    }
+
+    /// Move the first queued command that would put `switch_id`
+    /// into `state` to the front of the queue
+    fn promote(&mut self, switch_id: i32, state: SwitchState) -> bool {
+        let found = self.pending.iter()
+            .position(|o| o.repeat == 0 && o.cmd.switch_id == switch_id && o.cmd.direction == state);
+        if let Some(idx) = found {
+            if let Some(o) = self.pending.remove(idx) {
+                self.pending.push_front(o);
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    fn next_due(&self) -> Option<DateTime<Utc>> {
+        self.pending.front().map(|next| {
+            ::std::cmp::max(self.last_sent + self.gap, next.not_before)
+        })
+    }
+}
+
+impl Dispatcher {
+    pub fn new(rx: Receiver<ChannelMessage>, clock: SharedClock, transport: Box<dyn Transport>) -> Self {
+        Self::with_config(&CONFIG, rx, clock, transport)
+    }
+
+    fn with_config(config: &'static Config, rx: Receiver<ChannelMessage>, clock: SharedClock, transport: Box<dyn Transport>) -> Self {
+        Self {
+            queues: HashMap::new(),
+            tracker: SwitchTracker::new(),
+            presence: Presence::new(&config.presence),
+            held: HashMap::new(),
+            cutoffs: HashSet::new(),
+            clock,
+            transport,
+            config,
+            persist: true,
+            simulated: false,
+            rx,
+            saved: None,
+        }
+    }
+
+    /// Pick up where the last Dispatcher stopped
+    pub fn resume(saved: SavedDispatch, clock: SharedClock, transport: Box<dyn Transport>) -> Result<Self, Error> {
+        let state = saved.lock().unwrap_or_else(|e| e.into_inner()).take()
+            .ok_or_else(|| Error::other("Dispatcher state is missing, is another Dispatcher running?"))?;
+        let mut ret = Self::new(state.rx, clock, transport);
+        ret.queues = state.queues;
+        ret.tracker = state.tracker;
+        ret.presence = state.presence;
+        ret.held = state.held;
+        ret.cutoffs = state.cutoffs;
+        ret.saved = Some(saved);
+        Ok(ret)
+    }
+
+    /// Stop the Dispatcher from reading or writing anything
+    /// in the database and from holding or resending anything
+    /// based on what the remotes report, so every command it
+    /// would send goes out
+    pub fn simulate(mut self) -> Self {
+        self.persist = false;
+        self.simulated = true;
+        self
+    }
+
+    pub fn run(mut self) -> Result<(), Error> {
+        self.restore_on_times();
+        loop {
+            if let Some(msg) = self.wait()? {
+                info!(target: "robohome", "{}", msg);
+                match msg {
+                    ChannelMessage::DispatcherEnqueue(cmd) => self.enqueue(cmd),
+                    ChannelMessage::DispatcherStateReport(report) => self.confirm(&report),
+                    ChannelMessage::DispatcherHeartbeat(remote_id, at) => self.heartbeat(remote_id, at),
+                    ChannelMessage::DispatcherRecord(cmd, outcome) => {
+                        let now = self.clock.now();
+                        self.record(&cmd, &outcome, now);
+                    },
+                    _ => (),
+                }
+            }
+            self.step()?;
+        }
+    }
+
+    /// Run every periodic check and send anything that is due
+    pub fn step(&mut self) -> Result<(), Error> {
+        self.check_feedback();
+        self.check_presence();
+        self.check_max_on(self.clock.now());
+        self.flush()
+    }
+
+    /// Block until either a new message arrives, the
+    /// next queued command is due, a switch needs to have
+    /// its feedback checked or a remote or held command
+    /// times out
+    fn wait(&self) -> Result<Option<ChannelMessage>, Error> {
+        let due = if let Some(due) = self.next_due() {
+            due
+        } else {
+            return Ok(Some(self.rx.recv()?));
+        };
+        let wait = (due - self.clock.now()).to_std().unwrap_or(StdDuration::from_millis(0));
+        match self.rx.recv_timeout(wait) {
+            Ok(msg) => Ok(Some(msg)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(Error::Rec(RecvError)),
+        }
+    }
+
+    pub fn enqueue(&mut self, cmd: FlipCommand) {
+        let config = &self.config.dispatch;
+        self.queues.entry(cmd.remote_id)
+            .or_insert_with(|| RemoteQueue::new(cmd.remote_id, config))
+            .pending.push_back(Outgoing::new(cmd));
+    }
+
+    pub fn next_due(&self) -> Option<DateTime<Utc>> {
+        let sends = self.queues.values().filter_map(RemoteQueue::next_due).min();
+        let check = if self.simulated {
+            None
+        } else {
+            self.tracker.next_check(self.grace())
+        };
+        let offline = self.presence.next_timeout();
+        let expiry = self.next_expiry();
+        let cutoff = self.next_cutoff();
+        vec![sends, check, offline, expiry, cutoff].into_iter().flatten().min()
+    }
+
+    fn restore_on_times(&mut self) {
+        if !self.persist {
+            return;
+        }
+        match get_on_times() {
+            Ok(times) => for (remote_id, switch_id, on_since) in times {
+                self.tracker.restore_on_since(remote_id, switch_id, on_since);
+            },
+            Err(e) => error!(target: "robohome", "Unable to restore switch on times\n{}", e),
+        }
+    }
+
+    fn confirm(&mut self, report: &StateReport) {
+        let before = self.tracker.on_since(report.remote_id, report.switch_id);
+        self.tracker.confirm(report);
+        self.persist_on_since(report.remote_id, report.switch_id, before);
+    }
+
+    fn command(&mut self, cmd: &FlipCommand, at: DateTime<Utc>) {
+        let before = self.tracker.on_since(cmd.remote_id, cmd.switch_id);
+        self.tracker.command(cmd, at);
+        self.persist_on_since(cmd.remote_id, cmd.switch_id, before);
+    }
+
+    /// Save a switch's on time if it has changed so the
+    /// maximum on time survives a restart
+    fn persist_on_since(&mut self, remote_id: i32, switch_id: i32, before: Option<DateTime<Utc>>) {
+        let after = self.tracker.on_since(remote_id, switch_id);
+        if before == after {
+            return;
+        }
+        if after.is_none() {
+            self.cutoffs.remove(&(remote_id, switch_id));
+        }
+        if !self.persist {
+            return;
+        }
+        if let Err(e) = save_on_time(remote_id, switch_id, after) {
+            error!(target: "robohome", "Unable to save on time for {}:{}\n{}", remote_id, switch_id, e);
+        }
+    }
+
+    /// The earliest time a switch that is currently on
+    /// will reach its maximum on time
+    fn next_cutoff(&self) -> Option<DateTime<Utc>> {
+        self.config.dispatch.switches.iter()
+            .filter(|s| !self.cutoffs.contains(&(s.remote_id, s.switch_id)))
+            .filter_map(|s| {
+                let max = Duration::seconds(s.max_on_secs?);
+                self.tracker.on_since(s.remote_id, s.switch_id).map(|on| on + max)
+            })
+            .min()
+    }
+
+    /// Turn off any switch that has been on for
+    /// longer than it is allowed to be
+    fn check_max_on(&mut self, now: DateTime<Utc>) {
+        for s in &self.config.dispatch.switches {
+            let key = (s.remote_id, s.switch_id);
+            let max = match s.max_on_secs {
+                Some(max) => Duration::seconds(max),
+                None => continue,
+            };
+            let on_since = match self.tracker.on_since(s.remote_id, s.switch_id) {
+                Some(on_since) => on_since,
+                None => continue,
+            };
+            if self.cutoffs.contains(&key) || on_since + max > now {
+                continue;
+            }
+            warn!(target: "robohome", "{}:{} has been on since {}, turning it off", s.remote_id, s.switch_id, on_since);
+            self.cutoffs.insert(key);
+            self.enqueue(FlipCommand {
+                flip_id: None,
+                scheduled: None,
+                reason: FlipReason::Cutoff,
+                remote_id: s.remote_id,
+                switch_id: s.switch_id,
+                direction: SwitchState::Off,
+            });
+        }
+    }
+
+    /// Log any switch that hasn't reported the state it was
+    /// commanded into and resend the command if configured to
+    fn check_feedback(&mut self) {
+        if self.simulated {
+            return;
+        }
+        let feedback = &self.config.feedback;
+        for mismatch in self.tracker.mismatches(self.clock.now(), self.grace()) {
+            warn!(target: "robohome", "State mismatch {}", mismatch);
+            if feedback.resend_on_mismatch && mismatch.resends < feedback.max_resends {
+                self.tracker.note_resend(mismatch.remote_id, mismatch.switch_id);
+                self.enqueue(mismatch.resend());
+            }
+        }
+    }
+
+    fn heartbeat(&mut self, remote_id: i32, at: DateTime<Utc>) {
+        if self.presence.heartbeat(remote_id, at) {
+            info!(target: "robohome", "Remote {} is back online", remote_id);
+            self.release(remote_id);
+        }
+    }
+
+    fn check_presence(&mut self) {
+        let now = self.clock.now();
+        for remote_id in self.presence.newly_offline(now) {
+            warn!(target: "robohome", "Remote {} has gone offline", remote_id);
+        }
+        self.expire_held(now);
+    }
+
+    /// Move everything waiting for an offline remote into the
+    /// held commands, keeping only the newest for each switch.
+    /// A cutoff that gets replaced is re-armed so it fires
+    /// again if the switch is still on
+    fn hold(held: &mut HashMap<(i32, i32), Held>, cutoffs: &mut HashSet<(i32, i32)>, queue: &mut RemoteQueue, now: DateTime<Utc>) {
+        for next in queue.pending.drain(..).filter(|o| o.repeat == 0) {
+            info!(target: "robohome", "Holding {} for offline remote", next.cmd);
+            let key = (next.cmd.remote_id, next.cmd.switch_id);
+            let replaced = held.insert(key, Held {
+                cmd: next.cmd,
+                since: now,
+            });
+            if let Some(old) = replaced {
+                if old.cmd.reason == FlipReason::Cutoff && held[&key].cmd.reason != FlipReason::Cutoff {
+                    cutoffs.remove(&key);
+                }
+            }
+        }
+    }
+
+    /// Put any held commands for a remote back into
+    /// its queue, oldest first
+    fn release(&mut self, remote_id: i32) {
+        self.expire_held(self.clock.now());
+        let keys: Vec<(i32, i32)> = self.held.keys().filter(|k| k.0 == remote_id).cloned().collect();
+        let mut released: Vec<Held> = keys.iter().filter_map(|k| self.held.remove(k)).collect();
+        released.sort_by_key(|h| h.since);
+        for h in released {
+            info!(target: "robohome", "Releasing held {}", h.cmd);
+            self.enqueue(h.cmd);
+        }
+    }
+
+    /// The earliest time a held command will be dropped as stale
+    fn next_expiry(&self) -> Option<DateTime<Utc>> {
+        let expiry = Duration::seconds(self.config.presence.hold_expiry_secs);
+        self.held.values()
+            .filter(|h| h.cmd.reason != FlipReason::Cutoff)
+            .map(|h| h.since + expiry)
+            .min()
+    }
+
+    /// Drop held commands that have waited too long, cutoffs
+    /// are never dropped since the switch is still on
+    fn expire_held(&mut self, now: DateTime<Utc>) {
+        let expiry = Duration::seconds(self.config.presence.hold_expiry_secs);
+        let stale: Vec<(i32, i32)> = self.held.iter()
+            .filter(|(_, h)| h.cmd.reason != FlipReason::Cutoff && now - h.since >= expiry)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in stale {
+            if let Some(h) = self.held.remove(&key) {
+                warn!(target: "robohome", "Dropping stale held {}", h.cmd);
+                self.record(&h.cmd, &Outcome::Expired, now);
+            }
+        }
+    }
+
+    /// Send at most one command from each remote's queue
+    /// that has waited out its gap, any retransmissions
+    /// go back to the front of the queue so they are sent
+    /// before the next command
+    pub fn flush(&mut self) -> Result<(), Error> {
+        let now = self.clock.now();
+        let ready: Vec<i32> = self.queues.iter()
+            .filter(|(_, queue)| queue.next_due().map(|due| due <= now).unwrap_or(false))
+            .map(|(remote_id, _)| *remote_id)
+            .collect();
+        for remote_id in ready {
+            self.flush_remote(remote_id, now)?;
+        }
+        Ok(())
+    }
+
+    fn flush_remote(&mut self, remote_id: i32, now: DateTime<Utc>) -> Result<(), Error> {
+        if !self.simulated && !self.presence.is_online(remote_id, now) {
+            if let Some(queue) = self.queues.get_mut(&remote_id) {
+                Self::hold(&mut self.held, &mut self.cutoffs, queue, now);
+            }
+            return Ok(());
+        }
+        let mut next = match self.queues.get_mut(&remote_id).and_then(|q| q.pending.pop_front()) {
+            Some(next) => next,
+            None => return Ok(()),
+        };
+        if next.repeat == 0 && self.is_redundant(&next.cmd) {
+            info!(target: "robohome", "Skipping {}, already in state", next.cmd);
+            self.record(&next.cmd, &Outcome::Skipped("already in state".to_owned()), now);
+            return Ok(());
+        }
+        if next.repeat == 0 && next.cmd.reason != FlipReason::Cutoff {
+            if let Err(violation) = interlock::check(&next.cmd, &self.tracker, &self.config.interlocks) {
+                self.refuse_or_defer(next, violation);
+                return Ok(());
+            }
+        }
+        debug!(target: "robohome:debug", "sending {} (repeat {})", next.cmd, next.repeat);
+        if let Err(e) = self.transport.send(&next.cmd, next.repeat) {
+            let now = self.clock.now();
+            let retry = match e {
+                Error::Undelivered(_) => false,
+                _ => next.failures + 1 < SEND_ATTEMPTS,
+            };
+            if retry {
+                let delay = next.retry_delay();
+                warn!(target: "robohome", "Unable to send {}, retrying in {}ms\n{}", next.cmd, delay.num_milliseconds(), e);
+                next.failures += 1;
+                next.not_before = now + delay;
+                if let Some(queue) = self.queues.get_mut(&remote_id) {
+                    queue.pending.push_front(next);
+                }
+                return Ok(());
+            }
+            error!(target: "robohome", "Unable to send {}\n{}", next.cmd, e);
+            self.record(&next.cmd, &Outcome::Failed(e.to_string()), now);
+            if next.cmd.reason == FlipReason::Cutoff {
+                self.cutoffs.remove(&(next.cmd.remote_id, next.cmd.switch_id));
+            }
+            return Ok(());
+        }
+        let sent = self.clock.now();
+        if next.repeat == 0 {
+            self.command(&next.cmd, sent);
+            self.record(&next.cmd, &Outcome::Sent, sent);
+        }
+        if let Some(queue) = self.queues.get_mut(&remote_id) {
+            queue.last_sent = sent;
+            if let Some(repeat) = next.next_repeat(sent, &self.config.dispatch) {
+                queue.pending.push_front(repeat);
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep the outcome of a command in the history and
+    /// let anyone listening for flip events know about it
+    fn record(&mut self, cmd: &FlipCommand, outcome: &Outcome, at: DateTime<Utc>) {
+        if let Err(e) = self.transport.event(&FlipEvent::new(cmd, outcome, at)) {
+            warn!(target: "robohome", "Unable to publish event for {}\n{}", cmd, e);
+        }
+        if !self.persist {
+            return;
+        }
+        if let Err(e) = record_history(cmd, outcome, at) {
+            error!(target: "robohome", "Unable to record history for {}\n{}", cmd, e);
+        }
+    }
+
+    /// A scheduled command for a switch that was last commanded
+    /// into the same state, resends are never redundant
+    fn is_redundant(&self, cmd: &FlipCommand) -> bool {
+        cmd.reason == FlipReason::Schedule
+            && !self.config.dispatch.always_send(cmd.remote_id, cmd.switch_id)
+            && self.tracker.commanded(cmd.remote_id, cmd.switch_id) == Some(cmd.direction)
+    }
+
+    fn grace(&self) -> Duration {
+        Duration::seconds(self.config.feedback.grace_secs)
+    }
+
+    /// A command that would break an interlock waits behind any
+    /// queued command that would clear the way for it, if there
+    /// isn't one it is dropped
+    fn refuse_or_defer(&mut self, mut next: Outgoing, violation: Violation) {
+        let remote_id = next.cmd.remote_id;
+        let mut promoted = 0;
+        let mut promoted_here = 0;
+        if next.deferred < MAX_DEFERRALS {
+            for &(switch, state) in &violation.needs {
+                if let Some(queue) = self.queues.get_mut(&switch.remote_id) {
+                    if queue.promote(switch.switch_id, state) {
+                        promoted += 1;
+                        if switch.remote_id == remote_id {
+                            promoted_here += 1;
+                        }
+                    }
+                }
+            }
+        }
+        if promoted == 0 {
+            warn!(target: "robohome", "Refusing {}: {}", next.cmd, violation);
+            let now = self.clock.now();
+            self.record(&next.cmd, &Outcome::Refused(violation.to_string()), now);
+            return;
+        }
+        info!(target: "robohome", "Deferring {}: {}", next.cmd, violation);
+        next.deferred += 1;
+        if let Some(queue) = self.queues.get_mut(&remote_id) {
+            next.not_before = self.clock.now() + queue.gap;
+            queue.pending.insert(promoted_here, next);
+        }
+    }
+}
+
+impl Drop for Dispatcher {
+    /// Runs when `run` returns and while unwinding from a
+    /// panic, a poisoned lock is still written to
+    fn drop(&mut self) {
+        let saved = match self.saved.take() {
+            Some(saved) => saved,
+            None => return,
+        };
+        let state = DispatchState {
+            rx: mem::replace(&mut self.rx, channel().1),
+            queues: mem::take(&mut self.queues),
+            tracker: mem::take(&mut self.tracker),
+            presence: mem::replace(&mut self.presence, Presence::new(&self.config.presence)),
+            held: mem::take(&mut self.held),
+            cutoffs: mem::take(&mut self.cutoffs),
+        };
+        *saved.lock().unwrap_or_else(|e| e.into_inner()) = Some(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use robohome_shared::clock::{Clock, ManualClock};
+    use transport::PrintTransport;
+    use std::{
+        io::{self, Write},
+        sync::{Arc, Mutex, mpsc::channel},
+    };
+    use toml::from_str;
+
+    /// Everything the PrintTransport wrote
+    #[derive(Clone, Default)]
+    struct Printed(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Printed {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().expect("printed lock poisoned").write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Printed {
+        /// The lines printed for commands to one remote
+        fn lines(&self, remote_id: i32) -> Vec<String> {
+            let out = self.0.lock().expect("printed lock poisoned");
+            let prefix = format!(" {}:", remote_id);
+            String::from_utf8_lossy(&out).lines()
+                .filter(|l| l.contains(&prefix))
+                .map(String::from)
+                .collect()
+        }
+    }
+
+    /// A config with only the required keys and anything in `extra`
+    fn config(extra: &str) -> &'static Config {
+        let toml = format!(r#"
+            db_conn_str = "postgres://localhost"
+            weather_uri = "http://localhost"
+            weather_attempts = 1
+            {}
+            [mq_config]
+            host = "localhost"
+            port = 5672
+            login = "guest"
+            password = "guest"
+        "#, extra);
+        Box::leak(Box::new(from_str(&toml).expect("test config")))
+    }
+
+    fn start() -> DateTime<Utc> {
+        Utc.ymd(2018, 6, 1).and_hms(12, 0, 0)
+    }
+
+    fn dispatcher(extra: &str) -> (Dispatcher, Arc<ManualClock>, Printed) {
+        let clock = Arc::new(ManualClock::new(start()));
+        let printed = Printed::default();
+        let transport = Box::new(PrintTransport::to(clock.clone(), Box::new(printed.clone())));
+        let (_tx, rx) = channel();
+        let mut d = Dispatcher::with_config(config(extra), rx, clock.clone(), transport);
+        d.persist = false;
+        (d, clock, printed)
+    }
+
+    fn cmd(remote_id: i32, switch_id: i32, direction: SwitchState, reason: FlipReason) -> FlipCommand {
+        FlipCommand {
+            flip_id: None,
+            scheduled: None,
+            reason,
+            remote_id,
+            switch_id,
+            direction,
+        }
+    }
+
+    /// A line the PrintTransport would print for a command sent at `at`
+    fn line(at: DateTime<Utc>, text: &str) -> String {
+        format!("{} {}", at.with_timezone(&CONFIG.timezone()).format("%a %Y-%m-%d %H:%M:%S %Z"), text)
+    }
+
+    /// Step the Dispatcher, moving the clock to whatever is due
+    /// next, until nothing is due before `end`
+    fn run_until(d: &mut Dispatcher, clock: &ManualClock, end: DateTime<Utc>) {
+        for _ in 0..1000 {
+            d.step().expect("step");
+            match d.next_due() {
+                Some(due) if due <= end => clock.set(::std::cmp::max(due, clock.now())),
+                _ => return,
+            }
+        }
+        panic!("dispatcher never settled");
+    }
+
+    #[test]
+    fn keeps_each_remotes_order_and_gap() {
+        let (mut d, clock, printed) = dispatcher(r#"
+            [dispatch]
+            min_gap_ms = 2000
+            [[dispatch.remotes]]
+            id = 2
+            min_gap_ms = 5000
+        "#);
+        d.enqueue(cmd(1, 1, SwitchState::On, FlipReason::Manual));
+        d.enqueue(cmd(2, 1, SwitchState::On, FlipReason::Manual));
+        d.enqueue(cmd(1, 2, SwitchState::On, FlipReason::Manual));
+        d.enqueue(cmd(2, 2, SwitchState::On, FlipReason::Manual));
+        d.enqueue(cmd(1, 1, SwitchState::Off, FlipReason::Manual));
+        run_until(&mut d, &clock, start() + Duration::minutes(1));
+        assert_eq!(printed.lines(1), vec![
+            line(start(), "1:1 On (manual)"),
+            line(start() + Duration::seconds(2), "1:2 On (manual)"),
+            line(start() + Duration::seconds(4), "1:1 Off (manual)"),
+        ]);
+        assert_eq!(printed.lines(2), vec![
+            line(start(), "2:1 On (manual)"),
+            line(start() + Duration::seconds(5), "2:2 On (manual)"),
+        ]);
+    }
+
+    #[test]
+    fn sends_right_away_once_the_gap_has_passed() {
+        let (mut d, clock, printed) = dispatcher("[dispatch]\nmin_gap_ms = 2000");
+        d.enqueue(cmd(1, 1, SwitchState::On, FlipReason::Manual));
+        run_until(&mut d, &clock, start() + Duration::minutes(1));
+        let later = start() + Duration::seconds(10);
+        clock.set(later);
+        d.enqueue(cmd(1, 2, SwitchState::On, FlipReason::Manual));
+        assert_eq!(d.next_due(), Some(start() + Duration::seconds(2)));
+        d.step().expect("step");
+        assert_eq!(printed.lines(1), vec![
+            line(start(), "1:1 On (manual)"),
+            line(later, "1:2 On (manual)"),
+        ]);
+    }
+
+    type Recorded<T> = Arc<Mutex<Vec<T>>>;
+
+    /// Fails every send, keeping when each was tried
+    /// and the outcome of every event
+    struct Failing {
+        clock: SharedClock,
+        error: fn() -> Error,
+        tries: Recorded<DateTime<Utc>>,
+        outcomes: Recorded<&'static str>,
+    }
+
+    impl Transport for Failing {
+        fn send(&mut self, _cmd: &FlipCommand, _repeat: u8) -> Result<(), Error> {
+            self.tries.lock().expect("tries lock poisoned").push(self.clock.now());
+            Err((self.error)())
+        }
+
+        fn event(&mut self, event: &FlipEvent) -> Result<(), Error> {
+            self.outcomes.lock().expect("outcomes lock poisoned").push(event.outcome);
+            Ok(())
+        }
+    }
+
+    fn failing(error: fn() -> Error) -> (Dispatcher, Arc<ManualClock>, Recorded<DateTime<Utc>>, Recorded<&'static str>) {
+        let clock = Arc::new(ManualClock::new(start()));
+        let tries = Arc::new(Mutex::new(vec![]));
+        let outcomes = Arc::new(Mutex::new(vec![]));
+        let transport = Box::new(Failing {
+            clock: clock.clone(),
+            error,
+            tries: tries.clone(),
+            outcomes: outcomes.clone(),
+        });
+        let (_tx, rx) = channel();
+        let mut d = Dispatcher::with_config(config(""), rx, clock.clone(), transport);
+        d.persist = false;
+        (d, clock, tries, outcomes)
+    }
+
+    #[test]
+    fn sends_every_repeat_before_the_next_command() {
+        let (mut d, clock, printed) = dispatcher(r#"
+            [dispatch]
+            min_gap_ms = 0
+            [[dispatch.switches]]
+            remote_id = 1
+            switch_id = 1
+            repeat = 2
+            repeat_gap_ms = 1000
+        "#);
+        d.enqueue(cmd(1, 1, SwitchState::On, FlipReason::Manual));
+        d.enqueue(cmd(1, 2, SwitchState::On, FlipReason::Manual));
+        run_until(&mut d, &clock, start() + Duration::minutes(1));
+        assert_eq!(printed.lines(1), vec![
+            line(start(), "1:1 On (manual)"),
+            line(start() + Duration::seconds(1), "1:1 On (manual, repeat 1)"),
+            line(start() + Duration::seconds(2), "1:1 On (manual, repeat 2)"),
+            line(start() + Duration::seconds(2), "1:2 On (manual)"),
+        ]);
+    }
+
+    #[test]
+    fn backs_off_between_failed_sends() {
+        let (mut d, clock, tries, outcomes) = failing(|| Error::Other("broker gone".to_owned()));
+        d.enqueue(cmd(1, 1, SwitchState::On, FlipReason::Manual));
+        run_until(&mut d, &clock, start() + Duration::minutes(5));
+        let waits: Vec<i64> = tries.lock().unwrap().windows(2)
+            .map(|w| (w[1] - w[0]).num_milliseconds())
+            .collect();
+        assert_eq!(waits, vec![500, 1000, 2000, 4000]);
+        assert_eq!(waits.len() + 1, SEND_ATTEMPTS as usize);
+        assert_eq!(*outcomes.lock().unwrap(), vec!["failed"]);
+        assert_eq!(d.next_due(), None);
+    }
+
+    #[test]
+    fn undelivered_sends_are_not_retried() {
+        let (mut d, clock, tries, outcomes) = failing(|| Error::Undelivered("no route".to_owned()));
+        d.enqueue(cmd(1, 1, SwitchState::On, FlipReason::Manual));
+        run_until(&mut d, &clock, start() + Duration::minutes(5));
+        assert_eq!(tries.lock().unwrap().len(), 1);
+        assert_eq!(*outcomes.lock().unwrap(), vec!["failed"]);
+    }
+
+    #[test]
+    fn retry_delay_is_capped() {
+        let mut next = Outgoing::new(cmd(1, 1, SwitchState::On, FlipReason::Manual));
+        assert_eq!(next.retry_delay(), Duration::milliseconds(MIN_RETRY_MS));
+        next.failures = 6;
+        assert_eq!(next.retry_delay(), Duration::milliseconds(MAX_RETRY_MS));
+        next.failures = u8::MAX;
+        assert_eq!(next.retry_delay(), Duration::milliseconds(MAX_RETRY_MS));
+    }
+
+    /// Switch 1:1 can only be on for a minute and 1:2
+    /// needs 1:1 on, remotes go offline after 30 seconds
+    /// without a heartbeat and held commands last two minutes
+    const CUTOFF: &str = r#"
+        [dispatch]
+        min_gap_ms = 0
+        [[dispatch.switches]]
+        remote_id = 1
+        switch_id = 1
+        max_on_secs = 60
+        [presence]
+        timeout_secs = 30
+        hold_expiry_secs = 120
+        [[interlocks]]
+        kind = "requires"
+        switch = { remote_id = 1, switch_id = 2 }
+        requires = { remote_id = 1, switch_id = 1 }
+    "#;
+
+    #[test]
+    fn cutoffs_skip_interlocks() {
+        let (mut d, clock, printed) = dispatcher(CUTOFF);
+        d.enqueue(cmd(1, 1, SwitchState::On, FlipReason::Manual));
+        d.enqueue(cmd(1, 2, SwitchState::On, FlipReason::Manual));
+        run_until(&mut d, &clock, start() + Duration::seconds(59));
+        assert_eq!(d.next_due(), Some(start() + Duration::seconds(60)));
+        run_until(&mut d, &clock, start() + Duration::minutes(5));
+        assert_eq!(printed.lines(1), vec![
+            line(start(), "1:1 On (manual)"),
+            line(start(), "1:2 On (manual)"),
+            line(start() + Duration::seconds(60), "1:1 Off (cutoff)"),
+        ]);
+        d.enqueue(cmd(1, 1, SwitchState::On, FlipReason::Manual));
+        d.enqueue(cmd(1, 1, SwitchState::Off, FlipReason::Manual));
+        run_until(&mut d, &clock, start() + Duration::minutes(10));
+        assert_eq!(printed.lines(1)[3..].to_vec(), vec![
+            line(start() + Duration::seconds(60), "1:1 On (manual)"),
+            line(start() + Duration::seconds(120), "1:1 Off (cutoff)"),
+        ], "only the cutoff turns 1:1 off while 1:2 is on");
+    }
+
+    #[test]
+    fn held_cutoffs_never_expire() {
+        let (mut d, clock, printed) = dispatcher(CUTOFF);
+        d.heartbeat(1, start());
+        d.enqueue(cmd(1, 1, SwitchState::On, FlipReason::Manual));
+        d.step().expect("step");
+        clock.set(start() + Duration::seconds(60));
+        d.enqueue(cmd(1, 3, SwitchState::On, FlipReason::Schedule));
+        d.step().expect("step");
+        assert!(d.held.contains_key(&(1, 1)));
+        assert!(d.held.contains_key(&(1, 3)));
+        clock.set(start() + Duration::minutes(10));
+        d.step().expect("step");
+        assert!(d.held.contains_key(&(1, 1)));
+        assert!(!d.held.contains_key(&(1, 3)));
+        assert_eq!(d.next_due(), None);
+        d.heartbeat(1, clock.now());
+        d.step().expect("step");
+        assert_eq!(printed.lines(1), vec![
+            line(start(), "1:1 On (manual)"),
+            line(start() + Duration::minutes(10), "1:1 Off (cutoff)"),
+        ]);
+    }
+
+    #[test]
+    fn only_scheduled_commands_are_redundant() {
+        let (mut d, clock, printed) = dispatcher("[dispatch]\nmin_gap_ms = 0");
+        d.enqueue(cmd(1, 1, SwitchState::On, FlipReason::Schedule));
+        d.enqueue(cmd(1, 1, SwitchState::On, FlipReason::Schedule));
+        d.enqueue(cmd(1, 1, SwitchState::On, FlipReason::Manual));
+        d.enqueue(cmd(1, 1, SwitchState::On, FlipReason::Mismatch));
+        d.enqueue(cmd(1, 1, SwitchState::On, FlipReason::Scene));
+        d.enqueue(cmd(1, 1, SwitchState::Off, FlipReason::Schedule));
+        run_until(&mut d, &clock, start() + Duration::minutes(1));
+        assert_eq!(printed.lines(1), vec![
+            line(start(), "1:1 On (schedule)"),
+            line(start(), "1:1 On (manual)"),
+            line(start(), "1:1 On (mismatch)"),
+            line(start(), "1:1 On (scene)"),
+            line(start(), "1:1 Off (schedule)"),
+        ]);
+    }
+
+    #[test]
+    fn always_send_switches_are_never_redundant() {
+        let (mut d, clock, printed) = dispatcher(r#"
+            [dispatch]
+            min_gap_ms = 0
+            [[dispatch.switches]]
+            remote_id = 1
+            switch_id = 1
+            always_send = true
+        "#);
+        d.enqueue(cmd(1, 1, SwitchState::On, FlipReason::Schedule));
+        d.enqueue(cmd(1, 1, SwitchState::On, FlipReason::Schedule));
+        run_until(&mut d, &clock, start() + Duration::minutes(1));
+        assert_eq!(printed.lines(1).len(), 2);
+    }
+
+    #[test]
+    fn wakes_for_presence_timeouts_and_held_expiry() {
+        let (mut d, clock, _) = dispatcher("[presence]\ntimeout_secs = 30\nhold_expiry_secs = 120");
+        d.heartbeat(1, start());
+        assert_eq!(d.next_due(), Some(start() + Duration::seconds(30)));
+        clock.set(start() + Duration::seconds(30));
+        d.enqueue(cmd(1, 1, SwitchState::On, FlipReason::Manual));
+        d.step().expect("step");
+        assert_eq!(d.next_due(), Some(start() + Duration::seconds(150)));
+    }
+}
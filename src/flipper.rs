@@ -1,90 +1,328 @@
-use super::{yesterday, ChannelMessage, Error};
-use data::{Flip, get_flips};
-use mq::send;
+use super::{yesterday, ChannelMessage, Error, CONFIG};
+use data::{Flip, SharedFlipSource};
+use robohome_shared::{
+    DstConfig, Nonexistent,
+    clock::{SharedClock, home_now},
+    message::{Fault, FlipCommand, Outcome, Severity, Worker},
+    schedule::{SharedProfile, lint, resolve_losers, resolve_instant, schedule_date},
+};
 
 use std::{
-    sync::mpsc::{Sender, Receiver}
+    cmp::Reverse,
+    mem,
+    sync::{Arc, Mutex, mpsc::{Sender, Receiver, channel}},
 };
 
-use chrono::{DateTime, Date, Local};
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
 
 pub struct Flipper {
-    flips: Vec<Flip>,
-    current_date: Date<Local>,
+    /// Today's flips along with the moment each should be sent,
+    /// flips that won't be sent carry the outcome to record
+    /// for them instead when they come due
+    flips: Vec<(DateTime<Tz>, Flip, Option<Outcome>)>,
+    /// The schedule day the loaded flips belong to
+    current_date: NaiveDate,
+    /// Flips that come due while paused are skipped
+    paused: bool,
+    /// The schedule profile flips are loaded for, kept by
+    /// the Supervisor so it survives a restart
+    profile: SharedProfile,
+    clock: SharedClock,
+    source: SharedFlipSource,
     tx: Sender<ChannelMessage>,
     rx: Receiver<ChannelMessage>,
+    /// Where the loaded flips are put back when this
+    /// Flipper stops, so a restart doesn't send them again
+    saved: Option<SavedFlipper>,
+}
+
+/// What a Flipper leaves behind when it stops, including
+/// its channel so messages sent while it is being restarted
+/// are still there for the next one
+pub struct FlipperState {
+    rx: Receiver<ChannelMessage>,
+    flips: Vec<(DateTime<Tz>, Flip, Option<Outcome>)>,
+    current_date: NaiveDate,
+    paused: bool,
 }
+
+/// Kept by the Supervisor across Flipper restarts, empty
+/// while a Flipper is running
+pub type SavedFlipper = Arc<Mutex<Option<FlipperState>>>;
+
+impl FlipperState {
+    /// The state of a Flipper that hasn't loaded anything yet,
+    /// so the first check loads today's flips
+    pub fn saved(rx: Receiver<ChannelMessage>, clock: &SharedClock) -> SavedFlipper {
+        Arc::new(Mutex::new(Some(Self {
+            rx,
+            flips: vec![],
+            current_date: schedule_date(yesterday(&**clock).naive_local()),
+            paused: false,
+        })))
+    }
+}
+
 impl Flipper {
-    pub fn new(tx: Sender<ChannelMessage>, rx: Receiver<ChannelMessage>) -> Self {
+    pub fn new(tx: Sender<ChannelMessage>, rx: Receiver<ChannelMessage>, clock: SharedClock,
+               source: SharedFlipSource, profile: SharedProfile) -> Self {
         Self {
             flips: vec![],
-            current_date: yesterday().date(),
+            current_date: schedule_date(yesterday(&*clock).naive_local()),
+            paused: false,
+            profile,
+            clock,
+            source,
             tx,
             rx,
+            saved: None,
         }
     }
 
+    /// Pick up where the last Flipper stopped
+    pub fn resume(saved: SavedFlipper, tx: Sender<ChannelMessage>, clock: SharedClock,
+                  source: SharedFlipSource, profile: SharedProfile) -> Result<Self, Error> {
+        let state = saved.lock().unwrap_or_else(|e| e.into_inner()).take()
+            .ok_or_else(|| Error::other("Flipper state is missing, is another Flipper running?"))?;
+        let mut ret = Self::new(tx, state.rx, clock, source, profile);
+        ret.flips = state.flips;
+        ret.current_date = state.current_date;
+        ret.paused = state.paused;
+        ret.saved = Some(saved);
+        Ok(ret)
+    }
+
     pub fn run(mut self) -> Result<(), Error> {
         loop {
             let msg = self.rx.recv()?;
             info!(target: "robohome", "{}", msg);
-            match msg {
-                ChannelMessage::FlipperCheck => {
-                    if self.is_out_of_date() {
-                        let _ = self.tx.send(ChannelMessage::FlipperOutOfDate);
-                        self.get_today()?;
-                        self.tx.send(ChannelMessage::FlipperUpdated)?;
-                    }
-                    self.send()?;
-                    self.tx.send(ChannelMessage::FlipperComplete)?;
-                },
-                ChannelMessage::FlipperRefresh => {
-                    self.get_today()?;
-                    self.prune_today();
-                    self.tx.send(ChannelMessage::FlipperUpdated)?;
-                },
-                _ => (),
+            if let Err(e) = self.handle(msg) {
+                match e.severity() {
+                    Severity::Recoverable => self.tx.send(ChannelMessage::Error(Fault::new(Worker::Flipper, Severity::Recoverable, &e)))?,
+                    _ => return Err(e),
+                }
             }
         }
     }
 
+    fn handle(&mut self, msg: ChannelMessage) -> Result<(), Error> {
+        match msg {
+            ChannelMessage::FlipperCheck => self.check()?,
+            ChannelMessage::FlipperRefresh => {
+                self.get_today()?;
+                self.prune_today();
+                self.tx.send(ChannelMessage::FlipperUpdated)?;
+            },
+            ChannelMessage::FlipperPause => self.paused = true,
+            ChannelMessage::FlipperResume => self.paused = false,
+            ChannelMessage::FlipperProfile(profile) => {
+                *self.profile.write().expect("profile lock poisoned") = profile;
+                self.get_today()?;
+                self.prune_today();
+                self.tx.send(ChannelMessage::FlipperUpdated)?;
+            },
+            _ => (),
+        }
+        let mut sending = self.flips.iter().filter(|(_, _, skip)| skip.is_none());
+        let remaining = sending.clone().count();
+        let next = sending.next_back().map(|(at, _, _)| at.with_timezone(&Utc));
+        self.tx.send(ChannelMessage::FlipperStatus(remaining, next))?;
+        Ok(())
+    }
+
+    /// Reload the schedule if the day has rolled over
+    /// and send everything that is due
+    pub fn check(&mut self) -> Result<(), Error> {
+        if self.is_out_of_date() {
+            let _ = self.tx.send(ChannelMessage::FlipperOutOfDate);
+            self.get_today()?;
+            self.tx.send(ChannelMessage::FlipperUpdated)?;
+        }
+        self.send()?;
+        self.tx.send(ChannelMessage::FlipperComplete)?;
+        Ok(())
+    }
+
+    /// When the next flip is due, or when the next schedule
+    /// day starts if there is nothing left today
+    pub fn next_event(&self) -> DateTime<Tz> {
+        if let Some((at, _, _)) = self.flips.last() {
+            return *at;
+        }
+        let start = self.current_date.succ().and_time(CONFIG.day_start());
+        let tz = CONFIG.timezone();
+        tz.from_local_datetime(&start).earliest()
+            .unwrap_or_else(|| tz.from_utc_datetime(&start))
+    }
+
     pub fn is_out_of_date(&self) -> bool {
-        self.current_date != Local::today()
+        self.current_date != schedule_date(home_now(&*self.clock).naive_local())
     }
 
+    /// Load today's flips, they are stored latest first
+    /// so the next one to send is always at the end. Flips that
+    /// lose to another at the same minute or land in a daylight
+    /// saving gap are kept so they can be recorded as skipped
     pub fn get_today(&mut self) -> Result<(), Error> {
-        self.flips = get_flips()?;
-        self.current_date = Local::today();
+        let today = schedule_date(home_now(&*self.clock).naive_local());
+        let profile = self.profile.read().expect("profile lock poisoned").clone();
+        let flips = self.source.flips(today, profile.as_deref())?;
+        for issue in lint(&flips) {
+            warn!(target: "robohome", "Schedule issue: {}", issue);
+        }
+        let tz = CONFIG.timezone();
+        // where a skipped time would have landed, so it is recorded
+        // when the clocks jump past it
+        let gap = DstConfig {
+            nonexistent: Nonexistent::ShiftForward,
+            ambiguous: CONFIG.dst.ambiguous,
+        };
+        let (winners, losers) = resolve_losers(flips);
+        let losers = losers.into_iter()
+            .map(|(flip, winner)| (flip, Some(Outcome::Skipped(format!("flip {} wins at the same minute", winner)))));
+        let mut flips: Vec<(DateTime<Tz>, Flip, Option<Outcome>)> = winners.into_iter().map(|flip| (flip, None)).chain(losers)
+            .filter_map(|(flip, skip)| {
+                match resolve_instant(&flip.time, today, &tz, &CONFIG.dst) {
+                    Some(at) => Some((at, flip, skip)),
+                    None => {
+                        info!(target: "robohome", "Skipping flip {}, its time does not exist today", flip.id);
+                        resolve_instant(&flip.time, today, &tz, &gap)
+                            .map(|at| (at, flip, Some(Outcome::Skipped("time does not exist today".to_owned()))))
+                    },
+                }
+            }).collect();
+        flips.sort_by_key(|(at, _, _)| Reverse(*at));
+        self.flips = flips;
+        self.current_date = today;
         Ok(())
     }
 
     pub fn prune_today(&mut self) {
-        let now = Local::now();
-        loop {
-            let pop = if let Some(ref flip) = self.flips.last() {
-                flip.time.lte(&now)
-            } else {
-                false
-            };
-            if pop {
-                let _ = self.flips.pop();
-            }
+        let now = home_now(&*self.clock);
+        while self.ready_to_send(&now) {
+            let _ = self.flips.pop();
         }
     }
     pub fn send(&mut self) -> Result<(), Error> {
-        let now = Local::now();
+        let now = home_now(&*self.clock);
         while self.ready_to_send(&now) {
-            let last = self.flips.pop().ok_or(Error::Other("Expected flip to exist".to_owned()))?;
-            send(last.remote_id, last.switch_id, last.direction)?;
+            let (at, last, skip) = self.flips.pop().ok_or(Error::Other("Expected flip to exist".to_owned()))?;
+            let mut cmd = FlipCommand::from(&last);
+            cmd.scheduled = Some(at.with_timezone(&Utc));
+            let skip = skip.or_else(|| if self.paused {
+                Some(Outcome::Skipped("schedule paused".to_owned()))
+            } else {
+                None
+            });
+            if let Some(outcome) = skip {
+                info!(target: "robohome", "Not sending flip {}, {}", last.id, outcome);
+                self.tx.send(ChannelMessage::FlipperSkipped(cmd, outcome))?;
+                continue;
+            }
+            self.tx.send(ChannelMessage::FlipperDispatch(cmd))?;
         }
         Ok(())
     }
 
-    fn ready_to_send(&self, now: &DateTime<Local>) -> bool {
-        if let Some(last) = self.flips.last() {
-            last.time.lte(now)
+    fn ready_to_send(&self, now: &DateTime<Tz>) -> bool {
+        if let Some((at, _, _)) = self.flips.last() {
+            at <= now
         } else {
             false
         }
     }
-}
\ No newline at end of file
+}
+
+impl Drop for Flipper {
+    /// Runs when `run` returns and while unwinding from a
+    /// panic, a poisoned lock is still written to
+    fn drop(&mut self) {
+        let saved = match self.saved.take() {
+            Some(saved) => saved,
+            None => return,
+        };
+        let state = FlipperState {
+            rx: mem::replace(&mut self.rx, channel().1),
+            flips: mem::take(&mut self.flips),
+            current_date: self.current_date,
+            paused: self.paused,
+        };
+        *saved.lock().unwrap_or_else(|e| e.into_inner()) = Some(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Timelike;
+    use data::{FlipSource, SwitchState, Time, TimeKind};
+    use robohome_shared::clock::{Clock, ManualClock};
+    use std::sync::{Arc, RwLock, mpsc::channel};
+
+    /// The same two flips every day, on two hours after
+    /// the day starts and off again twelve hours later
+    struct TwoFlips;
+
+    impl FlipSource for TwoFlips {
+        fn flips(&self, _day: NaiveDate, _profile: Option<&str>) -> Result<Vec<Flip>, Error> {
+            let start = CONFIG.day_start();
+            let start = (start.hour() * 60 + start.minute()) as i32;
+            Ok(vec![
+                flip(1, SwitchState::On, start + 2 * 60),
+                flip(2, SwitchState::Off, start + 14 * 60),
+            ])
+        }
+    }
+
+    fn flip(id: i32, direction: SwitchState, minute: i32) -> Flip {
+        Flip {
+            id,
+            direction,
+            time: Time::at_minute(minute, TimeKind::Custom, 0x7f),
+            switch_id: 1,
+            remote_id: 1,
+            priority: 0,
+        }
+    }
+
+    #[test]
+    fn sends_each_flip_once_through_a_day() {
+        let tz = CONFIG.timezone();
+        let day = NaiveDate::from_ymd(2018, 6, 1);
+        let start = tz.from_local_datetime(&day.and_time(CONFIG.day_start())).earliest()
+            .expect("day start exists")
+            .with_timezone(&Utc);
+        let manual = Arc::new(ManualClock::new(start));
+        let (tx, rx) = channel();
+        let (_flip_tx, flip_rx) = channel();
+        let profile = Arc::new(RwLock::new(None));
+        let mut flipper = Flipper::new(tx, flip_rx, manual.clone(), Arc::new(TwoFlips), profile);
+
+        flipper.check().expect("first check");
+        assert_eq!(flipper.current_date, day);
+        assert!(!rx.try_iter().any(|msg| matches!(msg, ChannelMessage::FlipperDispatch(_))));
+        let mut sent = vec![];
+        let mut reloads = 0;
+        for _ in 0..3 {
+            let next = flipper.next_event().with_timezone(&Utc);
+            assert!(next > manual.now());
+            manual.set(next);
+            flipper.check().expect("check");
+            for msg in rx.try_iter() {
+                match msg {
+                    ChannelMessage::FlipperDispatch(cmd) => {
+                        assert_eq!(cmd.scheduled, Some(next));
+                        sent.push((cmd.flip_id, cmd.direction));
+                    },
+                    ChannelMessage::FlipperOutOfDate => reloads += 1,
+                    _ => (),
+                }
+            }
+        }
+        assert_eq!(sent, vec![(Some(1), SwitchState::On), (Some(2), SwitchState::Off)]);
+        assert_eq!(reloads, 1);
+        assert_eq!(flipper.current_date, day.succ());
+        assert_eq!(flipper.flips.len(), 2);
+    }
+}
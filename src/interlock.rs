@@ -0,0 +1,157 @@
+use data::SwitchState;
+use robohome_shared::{Interlock, SwitchRef, message::FlipCommand};
+use state::SwitchTracker;
+
+/// A command that would break one of the configured
+/// interlocks, `needs` lists the switch states that
+/// would allow it to go through
+#[derive(Debug)]
+pub struct Violation {
+    pub rule: String,
+    pub needs: Vec<(SwitchRef, SwitchState)>,
+}
+
+impl ::std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "{}", self.rule)
+    }
+}
+
+/// Check a command against interlock rules using the
+/// tracked state of the other switches.
+///
+/// A switch we know nothing about yet, because it hasn't been
+/// commanded, reported or restored since startup, might be on.
+/// It blocks anything that needs it off and doesn't count as on
+/// for anything that needs it on, so nothing is let through on
+/// a guess. The blocked command is deferred behind a queued
+/// command that settles the switch or refused
+pub fn check(cmd: &FlipCommand, tracker: &SwitchTracker, rules: &[Interlock]) -> Result<(), Violation> {
+    let target = SwitchRef {
+        remote_id: cmd.remote_id,
+        switch_id: cmd.switch_id,
+    };
+    let state = |s: &SwitchRef| tracker.current(s.remote_id, s.switch_id);
+    let is_on = |s: &SwitchRef| state(s) == Some(SwitchState::On);
+    let might_be_on = |s: &SwitchRef| state(s) != Some(SwitchState::Off);
+    for rule in rules {
+        match (rule, cmd.direction) {
+            (Interlock::Exclusive { switches }, SwitchState::On) if switches.contains(&target) => {
+                let on: Vec<SwitchRef> = switches.iter().filter(|s| **s != target && might_be_on(s)).cloned().collect();
+                if !on.is_empty() {
+                    return Err(Violation {
+                        rule: format!("{} is exclusive with {}", target, join(&on)),
+                        needs: on.into_iter().map(|s| (s, SwitchState::Off)).collect(),
+                    });
+                }
+            },
+            (Interlock::Requires { switch, requires }, SwitchState::On) if *switch == target && !is_on(requires) => {
+                return Err(Violation {
+                    rule: format!("{} requires {} to be on", target, requires),
+                    needs: vec![(*requires, SwitchState::On)],
+                });
+            },
+            (Interlock::Requires { switch, requires }, SwitchState::Off) if *requires == target && might_be_on(switch) => {
+                return Err(Violation {
+                    rule: format!("{} requires {} to stay on", switch, target),
+                    needs: vec![(*switch, SwitchState::Off)],
+                });
+            },
+            (Interlock::MaxOn { max, switches }, SwitchState::On) if switches.contains(&target) => {
+                let on: Vec<SwitchRef> = switches.iter().filter(|s| **s != target && might_be_on(s)).cloned().collect();
+                if on.len() >= *max {
+                    return Err(Violation {
+                        rule: format!("no more than {} of {} can be on", max, join(switches)),
+                        needs: on.into_iter().map(|s| (s, SwitchState::Off)).collect(),
+                    });
+                }
+            },
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+fn join(switches: &[SwitchRef]) -> String {
+    switches.iter().map(|s| s.to_string()).collect::<Vec<String>>().join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use robohome_shared::message::FlipReason;
+
+    fn sw(switch_id: i32) -> SwitchRef {
+        SwitchRef {
+            remote_id: 1,
+            switch_id,
+        }
+    }
+
+    fn cmd(switch_id: i32, direction: SwitchState) -> FlipCommand {
+        FlipCommand {
+            flip_id: None,
+            scheduled: None,
+            reason: FlipReason::Manual,
+            remote_id: 1,
+            switch_id,
+            direction,
+        }
+    }
+
+    /// A tracker that last commanded each switch into a state,
+    /// switches that aren't listed are unknown
+    fn tracker(states: &[(i32, SwitchState)]) -> SwitchTracker {
+        let mut tracker = SwitchTracker::new();
+        for &(switch_id, state) in states {
+            tracker.command(&cmd(switch_id, state), Utc.ymd(2018, 6, 1).and_hms(12, 0, 0));
+        }
+        tracker
+    }
+
+    fn needs(result: Result<(), Violation>) -> Vec<(SwitchRef, SwitchState)> {
+        result.expect_err("expected a violation").needs
+    }
+
+    #[test]
+    fn exclusive() {
+        let rules = [Interlock::Exclusive { switches: vec![sw(1), sw(2)] }];
+        let on = cmd(1, SwitchState::On);
+        assert!(check(&on, &tracker(&[(2, SwitchState::Off)]), &rules).is_ok());
+        assert_eq!(needs(check(&on, &tracker(&[(2, SwitchState::On)]), &rules)), vec![(sw(2), SwitchState::Off)]);
+        assert_eq!(needs(check(&on, &tracker(&[]), &rules)), vec![(sw(2), SwitchState::Off)]);
+        assert!(check(&cmd(1, SwitchState::Off), &tracker(&[(2, SwitchState::On)]), &rules).is_ok());
+        assert!(check(&cmd(3, SwitchState::On), &tracker(&[(2, SwitchState::On)]), &rules).is_ok());
+    }
+
+    #[test]
+    fn requires_to_turn_on() {
+        let rules = [Interlock::Requires { switch: sw(1), requires: sw(2) }];
+        let on = cmd(1, SwitchState::On);
+        assert!(check(&on, &tracker(&[(2, SwitchState::On)]), &rules).is_ok());
+        assert_eq!(needs(check(&on, &tracker(&[(2, SwitchState::Off)]), &rules)), vec![(sw(2), SwitchState::On)]);
+        assert_eq!(needs(check(&on, &tracker(&[]), &rules)), vec![(sw(2), SwitchState::On)]);
+        assert!(check(&cmd(1, SwitchState::Off), &tracker(&[]), &rules).is_ok());
+    }
+
+    #[test]
+    fn requires_to_turn_off() {
+        let rules = [Interlock::Requires { switch: sw(1), requires: sw(2) }];
+        let off = cmd(2, SwitchState::Off);
+        assert!(check(&off, &tracker(&[(1, SwitchState::Off)]), &rules).is_ok());
+        assert_eq!(needs(check(&off, &tracker(&[(1, SwitchState::On)]), &rules)), vec![(sw(1), SwitchState::Off)]);
+        assert_eq!(needs(check(&off, &tracker(&[]), &rules)), vec![(sw(1), SwitchState::Off)]);
+        assert!(check(&cmd(2, SwitchState::On), &tracker(&[]), &rules).is_ok());
+    }
+
+    #[test]
+    fn max_on() {
+        let rules = [Interlock::MaxOn { max: 2, switches: vec![sw(1), sw(2), sw(3)] }];
+        let on = cmd(1, SwitchState::On);
+        assert!(check(&on, &tracker(&[(2, SwitchState::On), (3, SwitchState::Off)]), &rules).is_ok());
+        assert_eq!(needs(check(&on, &tracker(&[(2, SwitchState::On), (3, SwitchState::On)]), &rules)).len(), 2);
+        assert_eq!(needs(check(&on, &tracker(&[(2, SwitchState::On)]), &rules)).len(), 2);
+        assert!(check(&cmd(1, SwitchState::Off), &tracker(&[(2, SwitchState::On), (3, SwitchState::On)]), &rules).is_ok());
+    }
+}
@@ -4,59 +4,54 @@ extern crate serde_derive;
 extern crate serde_json;
 extern crate toml;
 extern crate amqp;
+extern crate amq_proto;
 extern crate chrono;
+extern crate chrono_tz;
 #[macro_use]
 extern crate log;
 extern crate env_logger;
 extern crate robohome_shared;
 
-use chrono::{DateTime, Local, Duration};
+use chrono::{DateTime, Duration};
+use chrono_tz::Tz;
 
+mod cli;
 mod counter;
+mod dispatch;
 mod flipper;
+mod interlock;
 mod mq;
+mod presence;
+mod preview;
+mod sim;
+mod state;
+mod status;
 mod supervisor;
+mod transport;
 
-use counter::Counter;
-use flipper::Flipper;
+use cli::Command;
 use supervisor::Supervisor;
 
-use robohome_shared::{data, message::ChannelMessage, error::Error, CONFIG};
+use robohome_shared::{clock::{Clock, SystemClock, home_now}, data, message::ChannelMessage, error::Error, CONFIG};
 
 fn main() -> Result<(), Error> {
     init_logging();
-    let (boss, tx, flip_rx) = Supervisor::new();
-    let tx1 = tx.clone();
-    let tx2 = tx.clone();
-    let tx3 = tx.clone();
-    let _fl_handle = ::std::thread::Builder::new().name("Flipper".to_owned()).spawn(move || {
-        let f = Flipper::new(tx1, flip_rx);
-        if let Err(e) = f.run() {
-            error!(target: "robohome", "Exiting flipper thread with error\n{}", e);
-        } else {
-            info!(target: "robohome", "Exiting flipper thread")
-        }
-    });
-    let _mq_handle = ::std::thread::Builder::new().name("MQ".to_owned()).spawn(move || {
-        if let Err(e) = mq::listen(tx2) {
-            error!(target: "robohome", "Exiting mq thread with error\n{}", e);
-        } else {
-            info!(target: "robohome", "Exiting mq thread");
-        }
-    });
-    let _count_handle = ::std::thread::Builder::new().name("Counter".to_owned()).spawn(move || {
-        let c = Counter::new(tx3);
-        if let Err(e) = c.run() {
-            error!(target: "robohome", "Exiting counter thread with error\n{}", e);
-        } else {
-            info!(target: "robohome", "Exiting counter thread");
-        }
-    });
-    boss.run()
+    let today = home_now(&SystemClock).date().naive_local();
+    match cli::parse(::std::env::args().skip(1).collect(), today)? {
+        Command::Run => run(),
+        Command::Simulate(opts) => sim::run(opts),
+        Command::Schedule { date, json } => preview::run(date, json),
+        Command::Query { remote_id, switch_id, at, json } => preview::query(remote_id, switch_id, at, json),
+    }
+}
+
+fn run() -> Result<(), Error> {
+    CONFIG.mq_config.check_tls()?;
+    Supervisor::start(SystemClock::shared())?.run()
 }
 
-pub fn yesterday() -> DateTime<Local> {
-    let today = Local::now();
+pub fn yesterday(clock: &dyn Clock) -> DateTime<Tz> {
+    let today = home_now(clock);
     let day = Duration::days(1);
     today - day
 }
@@ -1,57 +1,359 @@
-use amqp::{Session, Table, Basic,
-            protocol::basic::{BasicProperties, Deliver},
+use amqp::{Session, Table, TableEntry, Basic, AMQPError,
+            protocol::{self, basic::{BasicProperties, Deliver}},
             Consumer, Channel};
+use amq_proto::{Method, MethodFrame};
 use std::{
+    collections::HashSet,
     default::Default,
-    sync::mpsc::Sender,
+    sync::mpsc::{self, Sender},
+    thread::{self, sleep},
+    time::{Duration as StdDuration, Instant},
 };
 use data::SwitchState;
-use serde_json::to_vec;
+use robohome_shared::{
+    clock::{SystemClock, home_now},
+    message::{FlipEvent, RemoteCommand, StateReport, Status},
+    ExchangeConfig, QueueConfig,
+    query::{Explanation, expected_state, home_time},
+    schedule::SharedProfile,
+};
+use serde_json::{from_slice, from_str, from_value, to_vec, Value};
+use chrono::{DateTime, NaiveDateTime, Utc};
 use super::{
     CONFIG,
     Error,
     ChannelMessage
 };
 
-pub fn send(remote_id: i32, switch_id: i32, direction: SwitchState) -> Result<(), Error> {
-    let direction = direction.for_db();
-    let msg = Message {
-        switch_id: switch_id as u16,
-        direction: direction as u8,
-    };
-    let msg = to_vec(&msg)?;
-    let mut sess = get_session()?;
-    let mut ch = sess.open_channel(1)?;
-    let queue_name = "switches";
-    let binding_key = remote_id.to_string();
-    let _ex = ch.exchange_declare(queue_name, "topic", false, false, false, false, false, Table::new())?;
-    let _queue = ch.queue_declare(queue_name, false, false, false, false, false, Table::new())?;
-    let _bind = ch.queue_bind(queue_name, queue_name, &binding_key, false, Table::new())?;
-    let props: BasicProperties = Default::default();
-    ch.basic_publish(queue_name, &binding_key, true, false, props, msg)?;
+const MIN_BACKOFF_MS: u64 = 500;
+const MAX_BACKOFF_MS: u64 = 30 * 1000;
+/// How long to wait for the broker to confirm a publish
+/// before counting it as failed
+const CONFIRM_TIMEOUT_MS: u64 = 10 * 1000;
+/// The version of the command envelope this switcher understands
+const COMMAND_VERSION: u64 = 1;
+
+/// Keeps a single connection and channel to the broker open
+/// for publishing, the topology is declared once when the
+/// connection is opened and the connection is re-opened on the
+/// next publish after the broker drops it. The channel is put
+/// into confirm mode so a publish only succeeds once the broker
+/// has acked it
+#[derive(Default)]
+pub struct Publisher {
+    conn: Option<PublishConnection>,
+}
+
+struct PublishConnection {
+    // the session has to outlive the channel
+    _session: Session,
+    /// Only missing after a confirm timed out, the
+    /// connection is dropped right after that
+    ch: Option<Channel>,
+    bound: HashSet<String>,
+    events_declared: bool,
+    /// The delivery tag the broker will ack our next publish with
+    next_tag: u64,
+}
+
+impl Publisher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Publish a single command to a remote, `repeat` is 0 for
+    /// the original and counts up for each retransmission so
+    /// the remote can tell the copies apart. This is only tried
+    /// once, retrying is left to the Dispatcher so it never
+    /// blocks waiting for the broker to come back
+    pub fn send(&mut self, remote_id: i32, switch_id: i32, direction: SwitchState, repeat: u8) -> Result<(), Error> {
+        let direction = direction.for_db();
+        let msg = Message {
+            switch_id: switch_id as u16,
+            direction: direction as u8,
+            repeat,
+        };
+        let msg = to_vec(&msg)?;
+        let started = Instant::now();
+        match self.try_publish(&remote_id.to_string(), &msg) {
+            Ok(()) => {
+                let elapsed = started.elapsed();
+                debug!(target: "robohome:debug", "published to {} in {}ms", remote_id,
+                        elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64);
+                Ok(())
+            },
+            Err(e @ Error::Undelivered(_)) => Err(e),
+            Err(e) => {
+                self.conn = None;
+                Err(e)
+            },
+        }
+    }
+
+    /// Publish a flip event with the routing key
+    /// `flip.<outcome>.<remote_id>.<switch_id>`, events aren't
+    /// worth holding up the dispatcher so this is only tried once
+    pub fn event(&mut self, event: &FlipEvent) -> Result<(), Error> {
+        let exchange = match CONFIG.mq_config.events_exchange {
+            Some(ref exchange) => exchange,
+            None => return Ok(()),
+        };
+        let msg = to_vec(event)?;
+        let key = format!("flip.{}.{}.{}", event.outcome, event.remote_id, event.switch_id);
+        let ret = self.declare_events(exchange)
+            .and_then(|_| self.try_publish_json(exchange.name.as_str(), &key, msg));
+        if ret.is_err() {
+            self.conn = None;
+        }
+        ret
+    }
+
+    /// Publish a status snapshot to the exchange, like events
+    /// this is only tried once since another follows shortly
+    pub fn status(&mut self, status: &Status) -> Result<(), Error> {
+        let msg = to_vec(status)?;
+        let ret = self.try_publish_json(CONFIG.mq_config.exchange.name.as_str(), CONFIG.status.routing_key.as_str(), msg);
+        if ret.is_err() {
+            self.conn = None;
+        }
+        ret
+    }
+
+    fn declare_events(&mut self, exchange: &ExchangeConfig) -> Result<(), Error> {
+        let conn = self.connection()?;
+        if !conn.events_declared {
+            declare_exchange(conn.channel()?, exchange)?;
+            conn.events_declared = true;
+        }
+        Ok(())
+    }
+
+    fn try_publish_json(&mut self, exchange: &str, key: &str, msg: Vec<u8>) -> Result<(), Error> {
+        let conn = self.connection()?;
+        let props = BasicProperties {
+            content_type: Some("application/json".to_owned()),
+            delivery_mode: CONFIG.mq_config.delivery_mode(),
+            ..Default::default()
+        };
+        conn.channel()?.basic_publish(exchange, key, false, false, props, msg)?;
+        let tag = conn.next_tag;
+        conn.next_tag += 1;
+        conn.wait_for_confirm(tag)
+    }
+
+    fn try_publish(&mut self, binding_key: &str, msg: &[u8]) -> Result<(), Error> {
+        let conn = self.connection()?;
+        let mq = &CONFIG.mq_config;
+        if !conn.bound.contains(binding_key) {
+            let _bind = conn.channel()?.queue_bind(mq.switches.name.as_str(), mq.exchange.name.as_str(), binding_key, false, Table::new())?;
+            conn.bound.insert(binding_key.to_owned());
+        }
+        let props = BasicProperties {
+            delivery_mode: mq.delivery_mode(),
+            ..Default::default()
+        };
+        conn.channel()?.basic_publish(mq.exchange.name.as_str(), binding_key, true, false, props, msg.to_vec())?;
+        let tag = conn.next_tag;
+        conn.next_tag += 1;
+        conn.wait_for_confirm(tag)
+    }
+
+    fn connection(&mut self) -> Result<&mut PublishConnection, Error> {
+        if self.conn.is_none() {
+            let mut session = get_session()?;
+            let mq = &CONFIG.mq_config;
+            let mut ch = session.open_channel(mq.publish_channel)?;
+            declare_exchange(&mut ch, &mq.exchange)?;
+            declare_queue(&mut ch, &mq.switches)?;
+            let _select: protocol::confirm::SelectOk = ch.rpc(&protocol::confirm::Select { nowait: false }, "confirm.select-ok")?;
+            info!(target: "robohome", "Publisher connected");
+            self.conn = Some(PublishConnection {
+                _session: session,
+                ch: Some(ch),
+                bound: HashSet::new(),
+                events_declared: false,
+                next_tag: 1,
+            });
+        }
+        self.conn.as_mut().ok_or_else(|| Error::other("Publisher connection missing"))
+    }
+}
+
+impl PublishConnection {
+    fn channel(&mut self) -> Result<&mut Channel, Error> {
+        self.ch.as_mut().ok_or_else(|| Error::other("Publisher channel missing"))
+    }
+
+    /// Wait for the broker to ack or nack `tag`. Reading from the
+    /// channel blocks until a frame arrives so it is read on its own
+    /// thread, when no confirm comes within `CONFIRM_TIMEOUT_MS` the
+    /// publish fails and the channel is left to that thread, which
+    /// ends once the connection it belongs to is closed
+    fn wait_for_confirm(&mut self, tag: u64) -> Result<(), Error> {
+        let mut ch = self.ch.take().ok_or_else(|| Error::other("Publisher channel missing"))?;
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let ret = read_confirm(&mut ch, tag);
+            let _ = tx.send((ch, ret));
+        });
+        match rx.recv_timeout(StdDuration::from_millis(CONFIRM_TIMEOUT_MS)) {
+            Ok((ch, ret)) => {
+                self.ch = Some(ch);
+                ret
+            },
+            Err(_) => Err(Error::Other(format!("timed out waiting for the broker to confirm message {}", tag))),
+        }
+    }
+}
+
+/// Read frames until the broker acks or nacks `tag`, a message
+/// that can't be routed is returned before it is acked
+fn read_confirm(ch: &mut Channel, tag: u64) -> Result<(), Error> {
+    let mut returned = None;
+    loop {
+        let frame = ch.read()?;
+        // content frames following a return aren't method frames
+        let method = match MethodFrame::decode(&frame) {
+            Ok(method) => method,
+            Err(_) => continue,
+        };
+        match method.method_name() {
+            "basic.return" => {
+                let ret = protocol::basic::Return::decode(method).map_err(AMQPError::from)?;
+                returned = Some(format!("{} {}", ret.reply_code, ret.reply_text));
+            },
+            "basic.ack" => {
+                let ack = protocol::basic::Ack::decode(method).map_err(AMQPError::from)?;
+                if ack.delivery_tag == tag || (ack.multiple && ack.delivery_tag > tag) {
+                    return match returned {
+                        Some(reason) => Err(Error::Undelivered(format!("message returned as unroutable: {}", reason))),
+                        None => Ok(()),
+                    };
+                }
+            },
+            "basic.nack" => {
+                let nack = protocol::basic::Nack::decode(method).map_err(AMQPError::from)?;
+                if nack.delivery_tag == tag || (nack.multiple && nack.delivery_tag > tag) {
+                    // a nack is the broker failing, not the message, so it is worth retrying
+                    return Err(Error::Other(format!("message {} was nacked by the broker", tag)));
+                }
+            },
+            name => debug!(target: "robohome:debug", "ignoring {} while waiting for confirm", name),
+        }
+    }
+}
+
+fn declare_exchange(ch: &mut Channel, exchange: &ExchangeConfig) -> Result<(), Error> {
+    let _ex = ch.exchange_declare(exchange.name.as_str(), exchange.kind.as_str(), false,
+                                  exchange.durable, exchange.auto_delete, false, false, Table::new())?;
+    Ok(())
+}
+
+fn declare_queue(ch: &mut Channel, queue: &QueueConfig) -> Result<(), Error> {
+    let _queue = ch.queue_declare(queue.name.as_str(), false, queue.durable, false,
+                                  queue.auto_delete, false, Table::new())?;
+    Ok(())
+}
+
+/// Declare a queue and bind it to the exchange with its routing key
+fn bind_queue(ch: &mut Channel, queue: &QueueConfig) -> Result<(), Error> {
+    bind_queue_to(ch, CONFIG.mq_config.exchange.name.as_str(), queue)
+}
+
+fn bind_queue_to(ch: &mut Channel, exchange: &str, queue: &QueueConfig) -> Result<(), Error> {
+    declare_queue(ch, queue)?;
+    let _bind = ch.queue_bind(queue.name.as_str(), exchange, queue.routing_key.as_str(), false, Table::new())?;
     Ok(())
 }
 
 fn get_session() -> Result<Session, Error> {
-    let s = Session::new((&CONFIG.mq_config).into())?;
+    let s = Session::new(CONFIG.mq_config.options()?)?;
     Ok(s)
 }
 
+/// Consume commands and reports from the broker, when the
+/// connection drops it is re-opened with a backoff and the
+/// queues are declared again so a broker restart doesn't
+/// leave the switcher deaf. Only returns when the Supervisor
+/// has gone away
 pub fn listen(sender: Sender<ChannelMessage>) -> Result<(), Error> {
-    let l = MqListener::new(sender);
+    let mut backoff_ms = MIN_BACKOFF_MS;
+    loop {
+        match consume(&sender) {
+            Ok(()) => {
+                warn!(target: "robohome", "MQ listener lost its connection");
+                backoff_ms = MIN_BACKOFF_MS;
+            },
+            Err(e) => error!(target: "robohome", "MQ listener unable to connect\n{}", e),
+        }
+        sender.send(ChannelMessage::MqStatus(false))?;
+        info!(target: "robohome", "MQ listener reconnecting in {}ms", backoff_ms);
+        sleep(StdDuration::from_millis(backoff_ms));
+        backoff_ms = (backoff_ms * 2).min(MAX_BACKOFF_MS);
+    }
+}
+
+/// Declare the listener's queues and consume from them until
+/// the connection is lost, `start_consuming` only returns once
+/// reading from the broker fails
+fn consume(sender: &Sender<ChannelMessage>) -> Result<(), Error> {
+    let l = MqListener::new(sender.clone());
+    let states = StateListener::new(sender.clone());
+    let heartbeats = HeartbeatListener::new(sender.clone());
     let mut session = get_session()?;
-    let mut ch = session.open_channel(2)?;
-    let exchange_name = "switches";
-    let queue_name = "refresh";
-    let _ex = ch.exchange_declare(exchange_name, "topic", false, false, false, false, false, Table::new())?;
-    let _queue_decl = ch.queue_declare(queue_name, false, false, false, false, false, Table::new())?;
-    let _bind = ch.queue_bind(queue_name, exchange_name, "update", false, Table::new())?;
+    let mq = &CONFIG.mq_config;
+    let mut ch = session.open_channel(mq.listen_channel)?;
+    if let Some(ref dead_letter) = mq.dead_letter_exchange {
+        declare_exchange(&mut ch, dead_letter)?;
+        bind_queue_to(&mut ch, dead_letter.name.as_str(), &mq.dead_letter_queue)?;
+    }
+    declare_exchange(&mut ch, &mq.exchange)?;
     ch.basic_prefetch(10)?;
-    let _consumer_name = ch.basic_consume(l, queue_name, "update", false, false, false, false, Table::new());
+    bind_queue(&mut ch, &mq.refresh)?;
+    let _consumer_name = ch.basic_consume(l, mq.refresh.name.as_str(), "update", false, false, false, false, Table::new())?;
+    bind_queue(&mut ch, &mq.states)?;
+    let _state_consumer = ch.basic_consume(states, mq.states.name.as_str(), "state", false, false, false, false, Table::new())?;
+    bind_queue(&mut ch, &mq.heartbeats)?;
+    let _heartbeat_consumer = ch.basic_consume(heartbeats, mq.heartbeats.name.as_str(), "heartbeat", false, false, false, false, Table::new())?;
+    sender.send(ChannelMessage::MqStatus(true))?;
+    ch.start_consuming();
+    Ok(())
+}
+
+/// Answer queries on a connection of their own, replaying the
+/// schedule takes a trip to the database for each day so doing
+/// it on the listener's channel would hold up commands, reports
+/// and heartbeats. Reconnects with a backoff the same as `listen`
+pub fn respond(profile: SharedProfile) -> Result<(), Error> {
+    let mut backoff_ms = MIN_BACKOFF_MS;
+    loop {
+        match answer_queries(&profile) {
+            Ok(()) => {
+                warn!(target: "robohome", "MQ query responder lost its connection");
+                backoff_ms = MIN_BACKOFF_MS;
+            },
+            Err(e) => error!(target: "robohome", "MQ query responder unable to connect\n{}", e),
+        }
+        info!(target: "robohome", "MQ query responder reconnecting in {}ms", backoff_ms);
+        sleep(StdDuration::from_millis(backoff_ms));
+        backoff_ms = (backoff_ms * 2).min(MAX_BACKOFF_MS);
+    }
+}
+
+fn answer_queries(profile: &SharedProfile) -> Result<(), Error> {
+    let queries = QueryResponder::new(profile.clone());
+    let mut session = get_session()?;
+    let mq = &CONFIG.mq_config;
+    let mut ch = session.open_channel(mq.listen_channel)?;
+    declare_exchange(&mut ch, &mq.exchange)?;
+    ch.basic_prefetch(1)?;
+    bind_queue(&mut ch, &mq.query)?;
+    let _query_consumer = ch.basic_consume(queries, mq.query.name.as_str(), "query", false, false, false, false, Table::new())?;
     ch.start_consuming();
     Ok(())
 }
 
+/// Consumes the commands published to the refresh queue
 pub struct MqListener {
     sender: Sender<ChannelMessage>,
 }
@@ -63,48 +365,307 @@ impl MqListener {
         }
     }
 
-    fn send_error(&mut self, msg: &str) {
-        let msg = ChannelMessage::Error(msg.to_owned());
-        match self.sender.send(msg) {
+    /// Commands are a JSON object with the envelope `version`
+    /// and the command name in `command`, the bare string
+    /// `update` from before the envelope is still a refresh
+    fn parse(body: &str) -> Result<RemoteCommand, Error> {
+        if body == "update" {
+            return Ok(RemoteCommand::Refresh);
+        }
+        let mut value: Value = from_str(body)?;
+        match value.get("version").and_then(Value::as_u64) {
+            Some(COMMAND_VERSION) => (),
+            Some(version) => return Err(Error::Other(format!("Unsupported command version {}", version))),
+            None => return Err(Error::other("Command is missing its version")),
+        }
+        // commands without arguments won't deserialize with
+        // anything left in the object besides their name
+        if let Some(fields) = value.as_object_mut() {
+            fields.remove("version");
+        }
+        Ok(from_value(value)?)
+    }
+}
+
+impl Consumer for MqListener {
+    fn handle_delivery(&mut self, ch: &mut Channel, method: Deliver, _: BasicProperties, body: Vec<u8>) {
+        match ::std::str::from_utf8(&body) {
+            Ok(text) => match Self::parse(text) {
+                Ok(cmd) => if let Err(e) = self.sender.send(ChannelMessage::MqCommand(cmd)) {
+                    eprintln!("Catastrophic error when sending msg\n{}", e);
+                },
+                Err(e) => {
+                    warn!(target: "robohome", "Rejecting command {}\n{}", text, e);
+                    dead_letter(ch, &method, &body, "parse", &e.to_string());
+                },
+            },
             Err(e) => {
+                warn!(target: "robohome", "Rejecting command that isn't utf-8\n{}", e);
+                dead_letter(ch, &method, &body, "utf8", &e.to_string());
+            },
+        }
+        if let Err(e) = ch.basic_ack(method.delivery_tag, false) {
+            error!(target: "robohome", "Unable to send ack to MQ router\n{}", e);
+        }
+    }
+}
+
+/// Republish a message we couldn't make sense of to the dead
+/// letter exchange, the `x-failure-*` headers say what went
+/// wrong and the `x-original-*` headers where it came from
+fn dead_letter(ch: &mut Channel, method: &Deliver, body: &[u8], kind: &str, reason: &str) {
+    let exchange = match CONFIG.mq_config.dead_letter_exchange {
+        Some(ref exchange) => exchange,
+        None => return,
+    };
+    let props = BasicProperties {
+        headers: Some(dead_letter_headers(method, kind, reason, Utc::now())),
+        delivery_mode: CONFIG.mq_config.delivery_mode(),
+        ..Default::default()
+    };
+    if let Err(e) = ch.basic_publish(exchange.name.as_str(), &method.routing_key, false, false, props, body.to_vec()) {
+        error!(target: "robohome", "Unable to dead letter message from {}\n{}", method.routing_key, e);
+    }
+}
+
+fn dead_letter_headers(method: &Deliver, kind: &str, reason: &str, at: DateTime<Utc>) -> Table {
+    let mut headers = Table::new();
+    headers.insert("x-failure-kind".to_owned(), TableEntry::LongString(kind.to_owned()));
+    headers.insert("x-failure-reason".to_owned(), TableEntry::LongString(reason.to_owned()));
+    headers.insert("x-original-exchange".to_owned(), TableEntry::LongString(method.exchange.clone()));
+    headers.insert("x-original-routing-key".to_owned(), TableEntry::LongString(method.routing_key.clone()));
+    headers.insert("x-failed-at".to_owned(), TableEntry::LongString(at.to_rfc3339()));
+    headers
+}
+
+/// Consumes the state reports the remotes publish
+/// after acting on a command
+pub struct StateListener {
+    sender: Sender<ChannelMessage>,
+}
+
+impl StateListener {
+    pub fn new(sender: Sender<ChannelMessage>) -> Self {
+        Self {
+            sender,
+        }
+    }
+
+    fn parse(body: &[u8]) -> Result<StateReport, Error> {
+        let msg: StateMessage = from_slice(body)?;
+        Ok(StateReport {
+            remote_id: msg.remote_id,
+            switch_id: msg.switch_id as i32,
+            state: SwitchState::from_db(msg.direction as i32)?,
+            at: Utc::now(),
+        })
+    }
+}
+
+impl Consumer for StateListener {
+    fn handle_delivery(&mut self, ch: &mut Channel, method: Deliver, _: BasicProperties, body: Vec<u8>) {
+        match Self::parse(&body) {
+            Ok(report) => if let Err(e) = self.sender.send(ChannelMessage::MqStateReport(report)) {
                 eprintln!("Catastrophic error when sending msg\n{}", e);
             },
-            _ => (),
+            Err(e) => {
+                warn!(target: "robohome", "Unable to parse state report\n{}", e);
+                dead_letter(ch, &method, &body, "parse", &e.to_string());
+            },
+        }
+        if let Err(e) = ch.basic_ack(method.delivery_tag, false) {
+            error!(target: "robohome", "Unable to send ack to MQ router\n{}", e);
         }
     }
+}
 
-    fn send_reset(&mut self) {
-        match self.sender.send(ChannelMessage::MqUpdateFlip) {
-            Err(e) => eprintln!("Catastrophic error when sending msg\n{}", e),
-            _ => (),
+/// Consumes the heartbeats each remote publishes
+/// while it is running
+pub struct HeartbeatListener {
+    sender: Sender<ChannelMessage>,
+}
+
+impl HeartbeatListener {
+    pub fn new(sender: Sender<ChannelMessage>) -> Self {
+        Self {
+            sender,
         }
     }
 }
 
-impl Consumer for MqListener {
+impl Consumer for HeartbeatListener {
     fn handle_delivery(&mut self, ch: &mut Channel, method: Deliver, _: BasicProperties, body: Vec<u8>) {
-        if let Ok(ref msg) = String::from_utf8(body) {
-            info!(target: "robohome", "new mq message {}",msg);
-            if msg == "update" {
-                self.send_reset();
-            } else {
-                self.send_error(&format!("Unknown message content from MQ router {}", msg));
-            }
-        } else {
-            self.send_error("failed to decode utf-8")
+        match from_slice::<HeartbeatMessage>(&body) {
+            Ok(msg) => if let Err(e) = self.sender.send(ChannelMessage::MqHeartbeat(msg.remote_id, Utc::now())) {
+                eprintln!("Catastrophic error when sending msg\n{}", e);
+            },
+            Err(e) => {
+                warn!(target: "robohome", "Unable to parse heartbeat\n{}", e);
+                dead_letter(ch, &method, &body, "parse", &e.to_string());
+            },
         }
-        match ch.basic_ack(method.delivery_tag, false) {
-            Ok(_) => (),
-            Err(e) => self.send_error(&format!("Unable to send ack to MQ router\n{}", e)),
+        if let Err(e) = ch.basic_ack(method.delivery_tag, false) {
+            error!(target: "robohome", "Unable to send ack to MQ router\n{}", e);
         }
     }
 }
 
+/// Answers "what state should this switch be in" requests,
+/// replying to the queue named in the request's `reply_to`.
+/// Answers follow the profile currently in use
+pub struct QueryResponder {
+    profile: SharedProfile,
+}
+
+impl QueryResponder {
+    pub fn new(profile: SharedProfile) -> Self {
+        Self {
+            profile,
+        }
+    }
 
+    fn answer(&self, body: &[u8]) -> Result<Explanation, Error> {
+        let msg: QueryMessage = from_slice(body)?;
+        let at = match msg.at {
+            Some(ref at) => {
+                let local = NaiveDateTime::parse_from_str(at, "%Y-%m-%d %H:%M")
+                    .map_err(|e| Error::Other(format!("Invalid time {}\n{}", at, e)))?;
+                home_time(local)?
+            },
+            None => home_now(&SystemClock),
+        };
+        let profile = self.profile.read().expect("profile lock poisoned").clone();
+        expected_state(msg.remote_id, msg.switch_id, at, profile.as_deref())
+    }
+}
 
+impl Consumer for QueryResponder {
+    fn handle_delivery(&mut self, ch: &mut Channel, method: Deliver, props: BasicProperties, body: Vec<u8>) {
+        let reply = match self.answer(&body) {
+            Ok(explanation) => to_vec(&QueryReply { explanation: Some(explanation), error: None }),
+            Err(e) => to_vec(&QueryReply { explanation: None, error: Some(format!("{}", e)) }),
+        };
+        match (props.reply_to, reply) {
+            (Some(reply_to), Ok(reply)) => {
+                let reply_props = BasicProperties {
+                    correlation_id: props.correlation_id,
+                    content_type: Some("application/json".to_owned()),
+                    ..Default::default()
+                };
+                if let Err(e) = ch.basic_publish("", &reply_to, false, false, reply_props, reply) {
+                    error!(target: "robohome", "Unable to reply to query\n{}", e);
+                }
+            },
+            (None, _) => warn!(target: "robohome", "Query without a reply_to queue"),
+            (_, Err(e)) => error!(target: "robohome", "Unable to serialize query reply\n{}", e),
+        }
+        if let Err(e) = ch.basic_ack(method.delivery_tag, false) {
+            error!(target: "robohome", "Unable to send ack to MQ router\n{}", e);
+        }
+    }
+}
 
 #[derive(Deserialize, Serialize)]
 pub struct Message {
     switch_id: u16,
     direction: u8,
+    repeat: u8,
+}
+
+#[derive(Deserialize)]
+pub struct StateMessage {
+    remote_id: i32,
+    switch_id: u16,
+    direction: u8,
+}
+
+#[derive(Deserialize)]
+pub struct HeartbeatMessage {
+    remote_id: i32,
+}
+
+#[derive(Deserialize)]
+pub struct QueryMessage {
+    remote_id: i32,
+    switch_id: i32,
+    /// A time in the home timezone formatted as
+    /// `YYYY-MM-DD HH:MM`, now when missing
+    at: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct QueryReply {
+    explanation: Option<Explanation>,
+    error: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn parse(body: &str) -> Result<RemoteCommand, Error> {
+        MqListener::parse(body)
+    }
+
+    #[test]
+    fn bare_update_is_a_refresh() {
+        assert!(matches!(parse("update"), Ok(RemoteCommand::Refresh)));
+        assert!(parse("update\n").is_err());
+    }
+
+    #[test]
+    fn version_one_commands() {
+        assert!(matches!(parse(r#"{"version": 1, "command": "refresh"}"#), Ok(RemoteCommand::Refresh)));
+        assert!(matches!(parse(r#"{"version": 1, "command": "pause"}"#), Ok(RemoteCommand::Pause)));
+        match parse(r#"{"version": 1, "command": "flip-now", "remote_id": 2, "switch_id": 3, "state": "On"}"#) {
+            Ok(RemoteCommand::FlipNow { remote_id: 2, switch_id: 3, state: SwitchState::On }) => (),
+            other => panic!("expected flip-now, got {:?}", other),
+        }
+        match parse(r#"{"version": 1, "command": "set-profile", "profile": null}"#) {
+            Ok(RemoteCommand::SetProfile { profile: None }) => (),
+            other => panic!("expected set-profile, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn wrong_or_missing_version() {
+        assert!(parse(r#"{"version": 2, "command": "refresh"}"#).is_err());
+        assert!(parse(r#"{"version": "1", "command": "refresh"}"#).is_err());
+        assert!(parse(r#"{"command": "refresh"}"#).is_err());
+    }
+
+    #[test]
+    fn malformed_commands() {
+        assert!(parse("").is_err());
+        assert!(parse("refresh").is_err());
+        assert!(parse(r#"{"version": 1"#).is_err());
+        assert!(parse(r#"[1, "refresh"]"#).is_err());
+        assert!(parse(r#"{"version": 1}"#).is_err());
+        assert!(parse(r#"{"version": 1, "command": "explode"}"#).is_err());
+        assert!(parse(r#"{"version": 1, "command": "flip-now", "remote_id": 2}"#).is_err());
+    }
+
+    #[test]
+    fn dead_letter_headers_say_what_failed_and_where_from() {
+        let method = Deliver {
+            consumer_tag: "switcher".to_owned(),
+            delivery_tag: 7,
+            redelivered: false,
+            exchange: "robohome".to_owned(),
+            routing_key: "refresh".to_owned(),
+        };
+        let at = Utc.ymd(2018, 6, 1).and_hms(12, 0, 0);
+        let headers = dead_letter_headers(&method, "parse", "Command is missing its version", at);
+        let header = |name: &str| match headers.get(name) {
+            Some(TableEntry::LongString(value)) => value.clone(),
+            other => panic!("expected {} to be a string, got {:?}", name, other),
+        };
+        assert_eq!(headers.len(), 5);
+        assert_eq!(header("x-failure-kind"), "parse");
+        assert_eq!(header("x-failure-reason"), "Command is missing its version");
+        assert_eq!(header("x-original-exchange"), "robohome");
+        assert_eq!(header("x-original-routing-key"), "refresh");
+        assert_eq!(header("x-failed-at"), "2018-06-01T12:00:00+00:00");
+    }
 }
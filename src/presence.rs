@@ -0,0 +1,65 @@
+use robohome_shared::PresenceConfig;
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, Utc};
+
+/// Keeps track of the last heartbeat from each remote
+/// to decide if it is currently reachable
+pub struct Presence {
+    last_seen: HashMap<i32, DateTime<Utc>>,
+    online: HashMap<i32, bool>,
+    timeout: Duration,
+    assume_online: bool,
+}
+
+impl Presence {
+    pub fn new(config: &PresenceConfig) -> Self {
+        Self {
+            last_seen: HashMap::new(),
+            online: HashMap::new(),
+            timeout: Duration::seconds(config.timeout_secs),
+            assume_online: config.assume_online,
+        }
+    }
+
+    /// Record a heartbeat, returning true when the remote
+    /// was previously considered offline
+    pub fn heartbeat(&mut self, remote_id: i32, at: DateTime<Utc>) -> bool {
+        let was_online = self.is_online(remote_id, at);
+        self.last_seen.insert(remote_id, at);
+        self.online.insert(remote_id, true);
+        !was_online
+    }
+
+    /// A remote we have never heard from is treated as
+    /// online unless configured otherwise
+    pub fn is_online(&self, remote_id: i32, now: DateTime<Utc>) -> bool {
+        match self.last_seen.get(&remote_id) {
+            Some(last) => now - *last < self.timeout,
+            None => self.assume_online,
+        }
+    }
+
+    /// The earliest time a remote that is still counted as
+    /// online will miss its heartbeat
+    pub fn next_timeout(&self) -> Option<DateTime<Utc>> {
+        self.last_seen.iter()
+            .filter(|(remote_id, _)| self.online.get(remote_id).cloned().unwrap_or(false))
+            .map(|(_, last)| *last + self.timeout)
+            .min()
+    }
+
+    /// Every remote that has missed its heartbeat since
+    /// the last time this was called
+    pub fn newly_offline(&mut self, now: DateTime<Utc>) -> Vec<i32> {
+        let mut ret = vec![];
+        for (&remote_id, last) in &self.last_seen {
+            let online = now - *last < self.timeout;
+            if !online && self.online.insert(remote_id, false).unwrap_or(true) {
+                ret.push(remote_id);
+            }
+        }
+        ret
+    }
+}
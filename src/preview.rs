@@ -0,0 +1,68 @@
+use super::{Error, CONFIG};
+use data::SwitchState;
+use robohome_shared::{
+    clock::{SystemClock, home_now},
+    query::{expected_state, home_time},
+    schedule::{PlannedFlip, plan},
+};
+
+use serde_json::to_string_pretty;
+
+use chrono::{NaiveDate, NaiveDateTime};
+
+/// One line of the schedule preview
+#[derive(Serialize)]
+struct Row {
+    time: String,
+    remote_id: i32,
+    switch_id: i32,
+    direction: SwitchState,
+    source: String,
+}
+
+impl<'a> From<&'a PlannedFlip> for Row {
+    fn from(planned: &'a PlannedFlip) -> Self {
+        Self {
+            time: planned.at.to_rfc3339(),
+            remote_id: planned.flip.remote_id,
+            switch_id: planned.flip.switch_id,
+            direction: planned.flip.direction,
+            source: format!("flip {} ({:?})", planned.flip.id, planned.flip.time.kind),
+        }
+    }
+}
+
+/// Print every flip that will be sent on a schedule day
+/// either as a table or as JSON
+pub fn run(date: NaiveDate, json: bool) -> Result<(), Error> {
+    let planned = plan(date, CONFIG.profile.as_deref())?;
+    if json {
+        let rows: Vec<Row> = planned.iter().map(Row::from).collect();
+        println!("{}", to_string_pretty(&rows)?);
+        return Ok(());
+    }
+    println!("{:<22} {:>6} {:>6} {:<9} SOURCE", "TIME", "REMOTE", "SWITCH", "DIRECTION");
+    for p in &planned {
+        let row = Row::from(p);
+        println!("{:<22} {:>6} {:>6} {:<9} {}",
+                 p.at.format("%a %Y-%m-%d %H:%M"), row.remote_id, row.switch_id,
+                 format!("{:?}", row.direction), row.source);
+    }
+    Ok(())
+}
+
+/// Print the state a switch is expected to be in at a time
+/// along with the flip or command that put it there
+pub fn query(remote_id: i32, switch_id: i32, at: Option<NaiveDateTime>, json: bool) -> Result<(), Error> {
+    let at = match at {
+        Some(at) => home_time(at)?,
+        None => home_now(&SystemClock),
+    };
+    let explanation = expected_state(remote_id, switch_id, at, CONFIG.profile.as_deref())?;
+    if json {
+        println!("{}", to_string_pretty(&explanation)?);
+    } else {
+        println!("{}", explanation);
+    }
+    Ok(())
+}
@@ -0,0 +1,79 @@
+use super::{ChannelMessage, Error, CONFIG};
+use cli::SimOptions;
+use data::{Flip, FlipSource, get_flips};
+use dispatch::Dispatcher;
+use flipper::Flipper;
+use transport::PrintTransport;
+use robohome_shared::{
+    clock::{Clock, ManualClock, SharedClock},
+    schedule::time_on,
+};
+
+use std::{
+    cmp::{max, min},
+    sync::{Arc, RwLock, mpsc::channel},
+    thread::sleep,
+};
+
+use chrono::{Duration, NaiveDate, TimeZone, Utc};
+
+/// Flips stored in the database with solar times worked out
+/// for each simulated day, the saved times are only right for
+/// the day the daily updater last ran
+struct SimFlipSource;
+
+impl FlipSource for SimFlipSource {
+    fn flips(&self, day: NaiveDate, profile: Option<&str>) -> Result<Vec<Flip>, Error> {
+        let mut flips = get_flips(day, profile)?;
+        for flip in flips.iter_mut() {
+            flip.time = time_on(&flip.time, day);
+        }
+        Ok(flips)
+    }
+}
+
+/// Run the Flipper and Dispatcher against a virtual clock,
+/// printing every command instead of sending it. The clock
+/// jumps from one event to the next, or when a speed is
+/// provided runs that many times faster than real time
+pub fn run(opts: SimOptions) -> Result<(), Error> {
+    let tz = CONFIG.timezone();
+    let start = tz.from_local_datetime(&opts.from.and_time(CONFIG.day_start()))
+        .earliest()
+        .ok_or_else(|| Error::Other(format!("{} has no day start in {}", opts.from, tz)))?
+        .with_timezone(&Utc);
+    let end = start + Duration::days(opts.days);
+    let manual = Arc::new(ManualClock::new(start));
+    let clock: SharedClock = manual.clone();
+    let (tx, rx) = channel();
+    let (_flip_tx, flip_rx) = channel();
+    let (_dispatch_tx, dispatch_rx) = channel();
+    let profile = Arc::new(RwLock::new(CONFIG.profile.clone()));
+    let mut flipper = Flipper::new(tx, flip_rx, clock.clone(), Arc::new(SimFlipSource), profile);
+    let transport = Box::new(PrintTransport::new(clock.clone()));
+    let mut dispatcher = Dispatcher::new(dispatch_rx, clock.clone(), transport).simulate();
+    loop {
+        flipper.check()?;
+        for msg in rx.try_iter() {
+            if let ChannelMessage::FlipperDispatch(cmd) = msg {
+                dispatcher.enqueue(cmd);
+            }
+        }
+        dispatcher.step()?;
+        let now = manual.now();
+        let mut next = flipper.next_event().with_timezone(&Utc);
+        if let Some(due) = dispatcher.next_due() {
+            next = min(next, due);
+        }
+        let next = max(next, now + Duration::seconds(1));
+        if next >= end {
+            return Ok(());
+        }
+        if let Some(speed) = opts.speed {
+            if let Ok(wait) = (next - now).to_std() {
+                sleep(wait / speed.max(1));
+            }
+        }
+        manual.set(next);
+    }
+}
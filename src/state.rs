@@ -0,0 +1,188 @@
+use robohome_shared::{
+    data::SwitchState,
+    message::{FlipCommand, FlipReason, StateReport},
+};
+
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, Duration, Utc};
+
+/// Keeps track of what we last told each switch to do
+/// and what each switch last told us it was doing
+#[derive(Default)]
+pub struct SwitchTracker {
+    switches: HashMap<(i32, i32), SwitchRecord>,
+    /// Remotes that have sent at least one state report, only
+    /// their switches are checked for mismatches since older
+    /// remotes never report
+    reporting: HashSet<i32>,
+}
+
+#[derive(Default)]
+pub struct SwitchRecord {
+    pub commanded: Option<(SwitchState, DateTime<Utc>)>,
+    pub confirmed: Option<(SwitchState, DateTime<Utc>)>,
+    /// When the switch last went from off to on
+    pub on_since: Option<DateTime<Utc>>,
+    mismatch_reported: bool,
+    resends: u8,
+}
+
+impl SwitchRecord {
+    /// A switch is settled once a report at or after the last
+    /// command agrees with it
+    fn is_settled(&self) -> bool {
+        match (self.commanded, self.confirmed) {
+            (Some((commanded, sent)), Some((confirmed, at))) => commanded == confirmed && at >= sent,
+            (Some(_), None) => false,
+            _ => true,
+        }
+    }
+
+    fn observe(&mut self, state: SwitchState, at: DateTime<Utc>) {
+        match state {
+            SwitchState::On => if self.on_since.is_none() {
+                self.on_since = Some(at);
+            },
+            SwitchState::Off => self.on_since = None,
+        }
+    }
+}
+
+impl SwitchTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, remote_id: i32, switch_id: i32) -> Option<&SwitchRecord> {
+        self.switches.get(&(remote_id, switch_id))
+    }
+
+    /// Our best guess at the state of a switch, whichever of
+    /// the last command or last report is newer, falling back
+    /// to an on time restored from before a restart
+    pub fn current(&self, remote_id: i32, switch_id: i32) -> Option<SwitchState> {
+        let rec = self.get(remote_id, switch_id)?;
+        match (rec.commanded, rec.confirmed) {
+            (Some((commanded, sent)), Some((confirmed, at))) => if at >= sent {
+                Some(confirmed)
+            } else {
+                Some(commanded)
+            },
+            (None, None) => rec.on_since.map(|_| SwitchState::On),
+            (commanded, confirmed) => commanded.or(confirmed).map(|(state, _)| state),
+        }
+    }
+
+    pub fn command(&mut self, cmd: &FlipCommand, at: DateTime<Utc>) {
+        let rec = self.switches.entry((cmd.remote_id, cmd.switch_id)).or_default();
+        if rec.commanded.map(|(state, _)| state != cmd.direction).unwrap_or(true) {
+            rec.resends = 0;
+        }
+        rec.commanded = Some((cmd.direction, at));
+        rec.mismatch_reported = false;
+        rec.observe(cmd.direction, at);
+    }
+
+    pub fn commanded(&self, remote_id: i32, switch_id: i32) -> Option<SwitchState> {
+        self.get(remote_id, switch_id).and_then(|rec| rec.commanded).map(|(state, _)| state)
+    }
+
+    pub fn on_since(&self, remote_id: i32, switch_id: i32) -> Option<DateTime<Utc>> {
+        self.get(remote_id, switch_id).and_then(|rec| rec.on_since)
+    }
+
+    /// Put back an on time that was saved before a restart
+    pub fn restore_on_since(&mut self, remote_id: i32, switch_id: i32, at: DateTime<Utc>) {
+        let rec = self.switches.entry((remote_id, switch_id)).or_default();
+        rec.on_since = Some(at);
+    }
+
+    /// Count a resend caused by a mismatch, this is cleared
+    /// when the switch is commanded into a different state
+    pub fn note_resend(&mut self, remote_id: i32, switch_id: i32) {
+        if let Some(rec) = self.switches.get_mut(&(remote_id, switch_id)) {
+            rec.resends = rec.resends.saturating_add(1);
+        }
+    }
+
+    pub fn confirm(&mut self, report: &StateReport) {
+        self.reporting.insert(report.remote_id);
+        let rec = self.switches.entry((report.remote_id, report.switch_id)).or_default();
+        let was_settled = rec.is_settled();
+        rec.confirmed = Some((report.state, report.at));
+        rec.observe(report.state, report.at);
+        if was_settled && !rec.is_settled() {
+            rec.mismatch_reported = false;
+        }
+    }
+
+    /// The earliest time a currently unsettled switch
+    /// would be considered mismatched
+    pub fn next_check(&self, grace: Duration) -> Option<DateTime<Utc>> {
+        self.switches.iter()
+            .filter(|(&(remote_id, _), _)| self.reporting.contains(&remote_id))
+            .map(|(_, rec)| rec)
+            .filter(|rec| !rec.mismatch_reported && !rec.is_settled())
+            .filter_map(|rec| rec.commanded.map(|(_, sent)| sent + grace))
+            .min()
+    }
+
+    /// Every switch that has not confirmed the last command it
+    /// was sent within `grace`, each mismatch is only returned
+    /// once per command
+    pub fn mismatches(&mut self, now: DateTime<Utc>, grace: Duration) -> Vec<Mismatch> {
+        let mut ret = vec![];
+        for (&(remote_id, switch_id), rec) in self.switches.iter_mut() {
+            if !self.reporting.contains(&remote_id) || rec.mismatch_reported || rec.is_settled() {
+                continue;
+            }
+            if let Some((commanded, sent)) = rec.commanded {
+                if sent + grace > now {
+                    continue;
+                }
+                rec.mismatch_reported = true;
+                ret.push(Mismatch {
+                    remote_id,
+                    switch_id,
+                    commanded,
+                    confirmed: rec.confirmed.map(|(state, _)| state),
+                    resends: rec.resends,
+                });
+            }
+        }
+        ret
+    }
+}
+
+pub struct Mismatch {
+    pub remote_id: i32,
+    pub switch_id: i32,
+    pub commanded: SwitchState,
+    pub confirmed: Option<SwitchState>,
+    pub resends: u8,
+}
+
+impl Mismatch {
+    pub fn resend(&self) -> FlipCommand {
+        FlipCommand {
+            flip_id: None,
+            scheduled: None,
+            reason: FlipReason::Mismatch,
+            remote_id: self.remote_id,
+            switch_id: self.switch_id,
+            direction: self.commanded,
+        }
+    }
+}
+
+impl ::std::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match self.confirmed {
+            Some(confirmed) => write!(f, "{}:{} commanded {:?} but reported {:?}",
+                                    self.remote_id, self.switch_id, self.commanded, confirmed),
+            None => write!(f, "{}:{} commanded {:?} but never reported",
+                                    self.remote_id, self.switch_id, self.commanded),
+        }
+    }
+}
@@ -0,0 +1,39 @@
+use super::{ChannelMessage, Error};
+use data::ping;
+use mq::Publisher;
+
+use std::sync::mpsc::Receiver;
+
+/// Publishes the status snapshots the Supervisor puts together,
+/// on its own thread so a slow broker or database never holds
+/// up routing
+pub struct Reporter {
+    rx: Receiver<ChannelMessage>,
+    publisher: Publisher,
+}
+
+impl Reporter {
+    pub fn new(rx: Receiver<ChannelMessage>) -> Self {
+        Self {
+            rx,
+            publisher: Publisher::new(),
+        }
+    }
+
+    pub fn run(mut self) -> Result<(), Error> {
+        loop {
+            if let ChannelMessage::StatusPublish(mut status) = self.rx.recv()? {
+                status.db_connected = Some(match ping() {
+                    Ok(()) => true,
+                    Err(e) => {
+                        warn!(target: "robohome", "Database unreachable\n{}", e);
+                        false
+                    },
+                });
+                if let Err(e) = self.publisher.status(&status) {
+                    warn!(target: "robohome", "Unable to publish status\n{}", e);
+                }
+            }
+        }
+    }
+}
@@ -1,33 +1,531 @@
-use super::{ChannelMessage, Error};
+use super::{ChannelMessage, Error, CONFIG};
+use counter::Counter;
+use data::DbFlipSource;
+use dispatch::{Dispatcher, DispatchState, SavedDispatch};
+use flipper::{Flipper, FlipperState, SavedFlipper};
+use mq;
+use status::Reporter;
+use transport::MqTransport;
+use robohome_shared::{clock::SharedClock, message::{Fault, FlipCommand, FlipReason, RemoteCommand, Severity, Status, Worker, WorkerState, WorkerStatus}, schedule::SharedProfile};
 
 use std::{
-    sync::mpsc::{Sender, Receiver, channel}
+    collections::HashMap,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, RwLock, mpsc::{Sender, Receiver, RecvError, RecvTimeoutError, SendError, channel}},
+    thread::Builder,
+    time::Duration as StdDuration,
 };
+
+use chrono::{DateTime, Duration, Utc};
+
+/// How many times a worker can be restarted inside
+/// `RESTART_WINDOW_SECS` before the switcher gives up on it
+const MAX_RESTARTS: usize = 5;
+const RESTART_WINDOW_SECS: i64 = 60 * 60;
+const WORKERS: [Worker; 6] = [Worker::Flipper, Worker::Dispatcher, Worker::Mq, Worker::Counter, Worker::Status, Worker::Query];
+
+/// Owns the worker threads, routes messages between them
+/// and restarts any worker that stops with an error
 pub struct Supervisor {
     incoming: Receiver<ChannelMessage>,
+    /// Handed to each worker so it can reach the Supervisor
+    tx: Sender<ChannelMessage>,
     flip_ch: Sender<ChannelMessage>,
+    dispatch_ch: Sender<ChannelMessage>,
+    status_ch: Sender<ChannelMessage>,
+    clock: SharedClock,
+    /// The schedule profile in use, shared by the Flipper
+    /// and the query responder
+    profile: SharedProfile,
+    /// What the Flipper and Dispatcher leave behind when
+    /// they stop, picked up again when they restart
+    flipper: SavedFlipper,
+    dispatcher: SavedDispatch,
+    started: DateTime<Utc>,
+    /// When the next status is due, `None` when it is turned off
+    next_status: Option<DateTime<Utc>>,
+    mq_connected: bool,
+    flips_remaining: usize,
+    next_flip: Option<DateTime<Utc>>,
+    last_error: Option<String>,
+    /// Recoverable errors reported by each worker
+    errors: HashMap<Worker, u64>,
+    /// When each worker was last restarted, inside the restart
+    /// window once `prune_restarts` has run
+    restarts: HashMap<Worker, Vec<DateTime<Utc>>>,
+    states: HashMap<Worker, WorkerState>,
+    /// Messages for a worker that stopped before it could take them
+    undelivered: HashMap<Worker, Vec<ChannelMessage>>,
 }
 
 impl Supervisor {
-    pub fn new() -> (Self, Sender<ChannelMessage>, Receiver<ChannelMessage>) {
-        let (flip_ch, flip_rx) = channel();
+    /// Spawn every worker
+    pub fn start(clock: SharedClock) -> Result<Self, Error> {
         let (tx, incoming) = channel();
-        let ret = Self {
+        let profile = Arc::new(RwLock::new(CONFIG.profile.clone()));
+        let (flip_ch, flip_rx) = channel();
+        let flipper = FlipperState::saved(flip_rx, &clock);
+        spawn_flipper(&tx, &clock, &profile, &flipper)?;
+        let (dispatch_ch, dispatch_rx) = channel();
+        let dispatcher = DispatchState::saved(dispatch_rx);
+        spawn_dispatcher(&tx, &clock, &dispatcher)?;
+        spawn_mq(&tx)?;
+        spawn_query(&tx, &profile)?;
+        spawn_counter(&tx, &clock)?;
+        let status_ch = spawn_status(&tx)?;
+        let started = clock.now();
+        Ok(Self {
             incoming,
+            tx,
             flip_ch,
-        };
-        (ret, tx, flip_rx)
+            dispatch_ch,
+            status_ch,
+            clock,
+            profile,
+            flipper,
+            dispatcher,
+            started,
+            next_status: status_interval().map(|interval| started + interval),
+            mq_connected: false,
+            flips_remaining: 0,
+            next_flip: None,
+            last_error: None,
+            errors: HashMap::new(),
+            restarts: HashMap::new(),
+            states: WORKERS.iter().map(|w| (*w, WorkerState::Running)).collect(),
+            undelivered: HashMap::new(),
+        })
     }
-    pub fn run(self) -> Result<(), Error> {
+
+    pub fn run(mut self) -> Result<(), Error> {
         loop {
-            let msg = self.incoming.recv()?;
+            let msg = self.wait()?;
             info!(target: "robohome", "{}", msg);
             match msg {
-                ChannelMessage::Tick => self.flip_ch.send(ChannelMessage::FlipperCheck)?,
-                ChannelMessage::MqUpdateFlip => self.flip_ch.send(ChannelMessage::FlipperRefresh)?,
-                ChannelMessage::Error(msg) => return Err(Error::Other(msg)),
+                ChannelMessage::Tick => self.deliver(Worker::Flipper, ChannelMessage::FlipperCheck),
+                ChannelMessage::FlipperStatus(remaining, next) => {
+                    self.flips_remaining = remaining;
+                    self.next_flip = next;
+                },
+                ChannelMessage::FlipperDispatch(cmd) => self.deliver(Worker::Dispatcher, ChannelMessage::DispatcherEnqueue(cmd)),
+                ChannelMessage::FlipperSkipped(cmd, outcome) => self.deliver(Worker::Dispatcher, ChannelMessage::DispatcherRecord(cmd, outcome)),
+                ChannelMessage::MqCommand(cmd) => self.route(cmd),
+                ChannelMessage::MqStateReport(report) => self.deliver(Worker::Dispatcher, ChannelMessage::DispatcherStateReport(report)),
+                ChannelMessage::MqHeartbeat(remote_id, at) => self.deliver(Worker::Dispatcher, ChannelMessage::DispatcherHeartbeat(remote_id, at)),
+                ChannelMessage::MqStatus(connected) => {
+                    if self.mq_connected && !connected {
+                        warn!(target: "robohome", "MQ listener disconnected, refresh commands won't arrive until it reconnects");
+                    }
+                    self.mq_connected = connected;
+                },
+                ChannelMessage::Error(fault) => self.fault(fault)?,
                 _ => (),
             }
         }
     }
-}
\ No newline at end of file
+
+    /// Block until a message arrives, publishing
+    /// the status whenever it comes due
+    fn wait(&mut self) -> Result<ChannelMessage, Error> {
+        loop {
+            let due = match self.next_status {
+                Some(due) => due,
+                None => return Ok(self.incoming.recv()?),
+            };
+            let wait = (due - self.clock.now()).to_std().unwrap_or(StdDuration::from_millis(0));
+            match self.incoming.recv_timeout(wait) {
+                Ok(msg) => return Ok(msg),
+                Err(RecvTimeoutError::Timeout) => {
+                    self.publish_status();
+                    let now = self.clock.now();
+                    self.next_status = status_interval().map(|interval| now + interval);
+                },
+                Err(RecvTimeoutError::Disconnected) => return Err(Error::Rec(RecvError)),
+            }
+        }
+    }
+
+    /// Hand a snapshot of the switcher to the status worker
+    fn publish_status(&mut self) {
+        let now = self.clock.now();
+        self.prune_restarts(now);
+        let workers = WORKERS.iter()
+            .map(|worker| WorkerStatus {
+                worker: worker.to_string(),
+                state: self.states.get(worker).cloned().unwrap_or(WorkerState::Running),
+                restarts: self.restarts.get(worker).map(|r| r.len()).unwrap_or(0),
+                errors: self.errors.get(worker).cloned().unwrap_or(0),
+            }).collect();
+        self.deliver(Worker::Status, ChannelMessage::StatusPublish(Status {
+            at: now,
+            started: self.started,
+            uptime_secs: (now - self.started).num_seconds(),
+            workers,
+            flips_remaining: self.flips_remaining,
+            next_flip: self.next_flip,
+            last_error: self.last_error.clone(),
+            mq_connected: self.mq_connected,
+            db_connected: None,
+        }));
+    }
+
+    /// Pass a message on to a worker, when the worker has stopped
+    /// the message is kept and passed on once it has restarted
+    fn deliver(&mut self, worker: Worker, msg: ChannelMessage) {
+        if self.states.get(&worker) == Some(&WorkerState::GivenUp) {
+            warn!(target: "robohome", "{} has been given up on, dropping {}", worker, msg);
+            return;
+        }
+        let ch = match worker {
+            Worker::Flipper => &self.flip_ch,
+            Worker::Dispatcher => &self.dispatch_ch,
+            Worker::Status => &self.status_ch,
+            _ => {
+                warn!(target: "robohome", "{} doesn't take messages, dropping {}", worker, msg);
+                return;
+            },
+        };
+        if let Err(SendError(msg)) = ch.send(msg) {
+            warn!(target: "robohome", "{} has stopped, keeping {} until it restarts", worker, msg);
+            self.undelivered.entry(worker).or_default().push(msg);
+        }
+    }
+
+    /// Count recoverable errors, restart a worker that
+    /// stopped and stop the switcher on anything else
+    fn fault(&mut self, fault: Fault) -> Result<(), Error> {
+        self.last_error = Some(fault.to_string());
+        match fault.severity {
+            Severity::Recoverable => {
+                let count = self.errors.entry(fault.worker).or_insert(0);
+                *count += 1;
+                warn!(target: "robohome", "{} error #{}\n{}", fault.worker, count, fault.message);
+                Ok(())
+            },
+            Severity::WorkerFatal => {
+                error!(target: "robohome", "{} stopped\n{}", fault.worker, fault.message);
+                self.states.insert(fault.worker, WorkerState::Stopped);
+                self.restart(fault.worker)
+            },
+            Severity::Unrecoverable => Err(Error::Other(format!("{}", fault))),
+        }
+    }
+
+    /// Forget restarts that have fallen out of the restart window
+    fn prune_restarts(&mut self, now: DateTime<Utc>) {
+        let window_start = now - Duration::seconds(RESTART_WINDOW_SECS);
+        for restarts in self.restarts.values_mut() {
+            restarts.retain(|at| *at > window_start);
+        }
+    }
+
+    /// Start a stopped worker again, a worker that keeps stopping
+    /// is given up on and left stopped. The switcher carries on
+    /// without it so the given up state shows in the status for
+    /// monitoring to alert on
+    fn restart(&mut self, worker: Worker) -> Result<(), Error> {
+        let now = self.clock.now();
+        self.prune_restarts(now);
+        let restarts = self.restarts.entry(worker).or_default();
+        if restarts.len() >= MAX_RESTARTS {
+            error!(target: "robohome", "{} stopped {} times in the last {} seconds, giving up",
+                   worker, restarts.len() + 1, RESTART_WINDOW_SECS);
+            self.states.insert(worker, WorkerState::GivenUp);
+            self.undelivered.remove(&worker);
+            self.publish_status();
+            return Ok(());
+        }
+        restarts.push(now);
+        info!(target: "robohome", "Restarting {}", worker);
+        match worker {
+            Worker::Flipper => spawn_flipper(&self.tx, &self.clock, &self.profile, &self.flipper)?,
+            Worker::Dispatcher => spawn_dispatcher(&self.tx, &self.clock, &self.dispatcher)?,
+            Worker::Mq => spawn_mq(&self.tx)?,
+            Worker::Counter => spawn_counter(&self.tx, &self.clock)?,
+            Worker::Status => self.status_ch = spawn_status(&self.tx)?,
+            Worker::Query => spawn_query(&self.tx, &self.profile)?,
+        }
+        self.states.insert(worker, WorkerState::Running);
+        for msg in self.undelivered.remove(&worker).unwrap_or_default() {
+            self.deliver(worker, msg);
+        }
+        Ok(())
+    }
+
+    /// Pass a command from MQ on to the worker that handles it
+    fn route(&mut self, cmd: RemoteCommand) {
+        match cmd {
+            RemoteCommand::Refresh => self.deliver(Worker::Flipper, ChannelMessage::FlipperRefresh),
+            RemoteCommand::FlipNow { remote_id, switch_id, state } => {
+                self.deliver(Worker::Dispatcher, ChannelMessage::DispatcherEnqueue(FlipCommand {
+                    flip_id: None,
+                    scheduled: None,
+                    reason: FlipReason::Manual,
+                    remote_id,
+                    switch_id,
+                    direction: state,
+                }))
+            },
+            RemoteCommand::RunScene { scene } => match CONFIG.scene(&scene) {
+                Some(scene) => for sw in &scene.switches {
+                    self.deliver(Worker::Dispatcher, ChannelMessage::DispatcherEnqueue(FlipCommand {
+                        flip_id: None,
+                        scheduled: None,
+                        reason: FlipReason::Scene,
+                        remote_id: sw.remote_id,
+                        switch_id: sw.switch_id,
+                        direction: sw.state,
+                    }));
+                },
+                None => warn!(target: "robohome", "Unknown scene {}", scene),
+            },
+            RemoteCommand::Pause => self.deliver(Worker::Flipper, ChannelMessage::FlipperPause),
+            RemoteCommand::Resume => self.deliver(Worker::Flipper, ChannelMessage::FlipperResume),
+            RemoteCommand::SetProfile { profile } => self.deliver(Worker::Flipper, ChannelMessage::FlipperProfile(profile)),
+            RemoteCommand::Status => {
+                info!(target: "robohome", "Status: MQ listener {}, errors {:?}",
+                      if self.mq_connected { "connected" } else { "disconnected" }, self.errors);
+                self.publish_status();
+            },
+        }
+    }
+}
+
+/// Run a worker on its own thread, when it stops with an error
+/// or panics the Supervisor is told so it can start a new one
+fn spawn<F>(worker: Worker, tx: &Sender<ChannelMessage>, f: F) -> Result<(), Error>
+where F: FnOnce() -> Result<(), Error> + Send + 'static {
+    let tx = tx.clone();
+    let name = match worker {
+        Worker::Flipper => "Flipper",
+        Worker::Dispatcher => "Dispatcher",
+        Worker::Mq => "MQ",
+        Worker::Counter => "Counter",
+        Worker::Status => "Status",
+        Worker::Query => "Query",
+    };
+    let _handle = Builder::new().name(name.to_owned()).spawn(move || {
+        let e = match panic::catch_unwind(AssertUnwindSafe(f)) {
+            Ok(Ok(())) => {
+                info!(target: "robohome", "Exiting {} thread", worker);
+                return;
+            },
+            Ok(Err(e)) => e,
+            Err(cause) => {
+                let cause = cause.downcast_ref::<&str>().map(|s| s.to_string())
+                    .or_else(|| cause.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown cause".to_owned());
+                Error::Other(format!("{} thread panicked: {}", worker, cause))
+            },
+        };
+        error!(target: "robohome", "Exiting {} thread with error\n{}", worker, e);
+        let severity = match e.severity() {
+            Severity::Unrecoverable => Severity::Unrecoverable,
+            _ => Severity::WorkerFatal,
+        };
+        let _ = tx.send(ChannelMessage::Error(Fault::new(worker, severity, &e)));
+    }).map_err(|e| Error::Other(format!("Unable to spawn {} thread\n{}", worker, e)))?;
+    Ok(())
+}
+
+fn spawn_flipper(tx: &Sender<ChannelMessage>, clock: &SharedClock, profile: &SharedProfile, saved: &SavedFlipper) -> Result<(), Error> {
+    let (tx1, clock, profile, saved) = (tx.clone(), clock.clone(), profile.clone(), saved.clone());
+    spawn(Worker::Flipper, tx, move || Flipper::resume(saved, tx1, clock, DbFlipSource::shared(), profile)?.run())
+}
+
+fn spawn_dispatcher(tx: &Sender<ChannelMessage>, clock: &SharedClock, saved: &SavedDispatch) -> Result<(), Error> {
+    let (clock, saved) = (clock.clone(), saved.clone());
+    spawn(Worker::Dispatcher, tx, move || Dispatcher::resume(saved, clock, Box::new(MqTransport::new()))?.run())
+}
+
+fn spawn_mq(tx: &Sender<ChannelMessage>) -> Result<(), Error> {
+    let tx1 = tx.clone();
+    spawn(Worker::Mq, tx, move || mq::listen(tx1))
+}
+
+fn spawn_query(tx: &Sender<ChannelMessage>, profile: &SharedProfile) -> Result<(), Error> {
+    let profile = profile.clone();
+    spawn(Worker::Query, tx, move || mq::respond(profile))
+}
+
+fn spawn_counter(tx: &Sender<ChannelMessage>, clock: &SharedClock) -> Result<(), Error> {
+    let (tx1, clock) = (tx.clone(), clock.clone());
+    spawn(Worker::Counter, tx, move || Counter::new(tx1, clock).run())
+}
+
+fn spawn_status(tx: &Sender<ChannelMessage>) -> Result<Sender<ChannelMessage>, Error> {
+    let (status_ch, status_rx) = channel();
+    spawn(Worker::Status, tx, move || Reporter::new(status_rx).run())?;
+    Ok(status_ch)
+}
+
+fn status_interval() -> Option<Duration> {
+    match CONFIG.status.interval_secs {
+        0 => None,
+        secs => Some(Duration::seconds(secs as i64)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data::{Flip, FlipSource, SwitchState, Time, TimeKind};
+    use robohome_shared::clock::{Clock, ManualClock};
+    use transport::Transport;
+    use chrono::{NaiveDate, TimeZone, Timelike};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Wait for the next message from a worker, skipping
+    /// the ones `keep` doesn't care about
+    fn next<T>(rx: &Receiver<ChannelMessage>, keep: impl Fn(ChannelMessage) -> Option<T>) -> T {
+        loop {
+            let msg = rx.recv_timeout(StdDuration::from_secs(5)).expect("worker went quiet");
+            if let Some(ret) = keep(msg) {
+                return ret;
+            }
+        }
+    }
+
+    fn stopped(msg: ChannelMessage) -> Option<Fault> {
+        match msg {
+            ChannelMessage::Error(fault) => Some(fault),
+            _ => None,
+        }
+    }
+
+    fn dispatched(msg: ChannelMessage) -> Option<Option<i32>> {
+        match msg {
+            ChannelMessage::FlipperDispatch(cmd) => Some(cmd.flip_id),
+            _ => None,
+        }
+    }
+
+    /// The same two flips every day, two and fourteen hours
+    /// after the day starts. Panics while loading them once
+    /// `panic_after` loads have happened
+    struct Flips {
+        loads: AtomicUsize,
+        panic_after: usize,
+    }
+
+    impl FlipSource for Flips {
+        fn flips(&self, _day: NaiveDate, _profile: Option<&str>) -> Result<Vec<Flip>, Error> {
+            if self.loads.fetch_add(1, Ordering::SeqCst) >= self.panic_after {
+                panic!("source went away");
+            }
+            let start = CONFIG.day_start();
+            let start = (start.hour() * 60 + start.minute()) as i32;
+            Ok(vec![flip(1, start + 2 * 60), flip(2, start + 14 * 60)])
+        }
+    }
+
+    fn flip(id: i32, minute: i32) -> Flip {
+        Flip {
+            id,
+            direction: SwitchState::On,
+            time: Time::at_minute(minute, TimeKind::Custom, 0x7f),
+            switch_id: 1,
+            remote_id: 1,
+            priority: 0,
+        }
+    }
+
+    fn spawn_flips(tx: &Sender<ChannelMessage>, clock: &Arc<ManualClock>, saved: &SavedFlipper, panic_after: usize) {
+        let (tx1, clock, saved) = (tx.clone(), clock.clone(), saved.clone());
+        let source = Arc::new(Flips { loads: AtomicUsize::new(0), panic_after });
+        spawn(Worker::Flipper, tx, move || {
+            Flipper::resume(saved, tx1, clock, source, Arc::new(RwLock::new(None)))?.run()
+        }).expect("spawn flipper");
+    }
+
+    #[test]
+    fn restarted_flipper_does_not_resend_sent_flips() {
+        let tz = CONFIG.timezone();
+        let day = NaiveDate::from_ymd(2018, 6, 1);
+        let start = tz.from_local_datetime(&day.and_time(CONFIG.day_start())).earliest()
+            .expect("day start exists")
+            .with_timezone(&Utc);
+        let clock = Arc::new(ManualClock::new(start + Duration::hours(3)));
+        let shared: SharedClock = clock.clone();
+        let (tx, rx) = channel();
+        let (flip_ch, flip_rx) = channel();
+        let saved = FlipperState::saved(flip_rx, &shared);
+
+        spawn_flips(&tx, &clock, &saved, 1);
+        flip_ch.send(ChannelMessage::FlipperCheck).unwrap();
+        assert_eq!(next(&rx, dispatched), Some(1));
+        flip_ch.send(ChannelMessage::FlipperRefresh).unwrap();
+        assert_eq!(next(&rx, stopped).worker, Worker::Flipper);
+
+        // sent while the Flipper is stopped
+        flip_ch.send(ChannelMessage::FlipperCheck).unwrap();
+        spawn_flips(&tx, &clock, &saved, 1);
+        clock.set(start + Duration::hours(15));
+        flip_ch.send(ChannelMessage::FlipperCheck).unwrap();
+        assert_eq!(next(&rx, dispatched), Some(2));
+    }
+
+    /// Reports every command it sends, panicking instead
+    /// on any for remote `panic_on`
+    struct Panicky {
+        panic_on: i32,
+        sent: Sender<i32>,
+    }
+
+    impl Transport for Panicky {
+        fn send(&mut self, cmd: &FlipCommand, _repeat: u8) -> Result<(), Error> {
+            if cmd.remote_id == self.panic_on {
+                panic!("transport went away");
+            }
+            let _ = self.sent.send(cmd.remote_id);
+            Ok(())
+        }
+    }
+
+    fn spawn_panicky(tx: &Sender<ChannelMessage>, clock: &Arc<ManualClock>, saved: &SavedDispatch, panic_on: i32, sent: &Sender<i32>) {
+        let (clock, saved, sent) = (clock.clone(), saved.clone(), sent.clone());
+        spawn(Worker::Dispatcher, tx, move || {
+            Dispatcher::resume(saved, clock, Box::new(Panicky { panic_on, sent }))?.run()
+        }).expect("spawn dispatcher");
+    }
+
+    fn command(remote_id: i32, reason: FlipReason) -> ChannelMessage {
+        ChannelMessage::DispatcherEnqueue(FlipCommand {
+            flip_id: None,
+            scheduled: None,
+            reason,
+            remote_id,
+            switch_id: 1,
+            direction: SwitchState::On,
+        })
+    }
+
+    #[test]
+    fn restarted_dispatcher_keeps_its_state_and_messages() {
+        // remote ids far from any a real config would set up
+        let (first, second, third) = (9001, 9002, 9003);
+        let clock = Arc::new(ManualClock::new(Utc.ymd(2018, 6, 1).and_hms(12, 0, 0)));
+        let (tx, rx) = channel();
+        let (dispatch_ch, dispatch_rx) = channel();
+        let saved = DispatchState::saved(dispatch_rx);
+        let (sent_tx, sent) = channel();
+        for remote_id in &[first, second, third] {
+            dispatch_ch.send(ChannelMessage::DispatcherHeartbeat(*remote_id, clock.now())).unwrap();
+        }
+
+        spawn_panicky(&tx, &clock, &saved, second, &sent_tx);
+        dispatch_ch.send(command(first, FlipReason::Schedule)).unwrap();
+        assert_eq!(sent.recv_timeout(StdDuration::from_secs(5)), Ok(first));
+        dispatch_ch.send(command(second, FlipReason::Manual)).unwrap();
+        assert_eq!(next(&rx, stopped).worker, Worker::Dispatcher);
+
+        // sent while the Dispatcher is stopped, the first is
+        // redundant if the restarted Dispatcher remembers
+        // what it already sent
+        dispatch_ch.send(command(first, FlipReason::Schedule)).unwrap();
+        dispatch_ch.send(command(third, FlipReason::Manual)).unwrap();
+        spawn_panicky(&tx, &clock, &saved, 0, &sent_tx);
+        assert_eq!(sent.recv_timeout(StdDuration::from_secs(5)), Ok(third));
+        assert!(sent.recv_timeout(StdDuration::from_millis(200)).is_err());
+    }
+}
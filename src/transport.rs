@@ -0,0 +1,71 @@
+use super::Error;
+use mq::Publisher;
+use robohome_shared::{clock::{SharedClock, home_now}, message::{FlipCommand, FlipEvent}};
+
+use std::io::{Write, stdout};
+
+/// Where the Dispatcher sends commands once they
+/// have made it through the queue
+pub trait Transport {
+    fn send(&mut self, cmd: &FlipCommand, repeat: u8) -> Result<(), Error>;
+
+    /// Tell other services what happened to a command
+    fn event(&mut self, _event: &FlipEvent) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Publishes each command to the remotes over MQ
+#[derive(Default)]
+pub struct MqTransport {
+    publisher: Publisher,
+}
+
+impl MqTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Transport for MqTransport {
+    fn send(&mut self, cmd: &FlipCommand, repeat: u8) -> Result<(), Error> {
+        self.publisher.send(cmd.remote_id, cmd.switch_id, cmd.direction, repeat)
+    }
+
+    fn event(&mut self, event: &FlipEvent) -> Result<(), Error> {
+        self.publisher.event(event)
+    }
+}
+
+/// Prints each command instead of sending it, stamped
+/// with the time on the provided clock
+pub struct PrintTransport {
+    clock: SharedClock,
+    out: Box<dyn Write>,
+}
+
+impl PrintTransport {
+    pub fn new(clock: SharedClock) -> Self {
+        Self::to(clock, Box::new(stdout()))
+    }
+
+    /// Print to somewhere other than stdout
+    pub fn to(clock: SharedClock, out: Box<dyn Write>) -> Self {
+        Self {
+            clock,
+            out,
+        }
+    }
+}
+
+impl Transport for PrintTransport {
+    fn send(&mut self, cmd: &FlipCommand, repeat: u8) -> Result<(), Error> {
+        let now = home_now(&*self.clock);
+        let ret = if repeat == 0 {
+            writeln!(self.out, "{} {} ({})", now.format("%a %Y-%m-%d %H:%M:%S %Z"), cmd, cmd.reason)
+        } else {
+            writeln!(self.out, "{} {} ({}, repeat {})", now.format("%a %Y-%m-%d %H:%M:%S %Z"), cmd, cmd.reason, repeat)
+        };
+        ret.map_err(|e| Error::Other(format!("Unable to print {}: {}", cmd, e)))
+    }
+}